@@ -1,58 +1,235 @@
 use serde_json;
 
-#[allow(dead_code)]
-pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
+// Decode a single bencoded value, returning it along with the input left after it.
+pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<(serde_json::Value, &str)> {
     match encoded_value.chars().next() {
         Some('0'..='9') => {
-            if let Some((len, rest)) = encoded_value.split_once(':') {
-                if let Ok(len) = len.parse::<usize>() {
-                    return (rest[..len].to_string().into(), &rest[len..]);
-                }
-            }
+            let (len, rest) = encoded_value
+                .split_once(':')
+                .ok_or(anyhow::anyhow!("Missing ':' in string: {}", encoded_value))?;
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid string length: {}", len))?;
+            let s = rest.get(..len).ok_or(anyhow::anyhow!(
+                "String of length {} is longer than the remaining input",
+                len
+            ))?;
+            Ok((s.to_string().into(), &rest[len..]))
         }
         Some('i') => {
-            if let Some((n, rest)) =
-                encoded_value
-                    .split_at(1)
-                    .1
-                    .split_once('e')
-                    .and_then(|(digits, rest)| {
-                        let n = digits.parse::<i64>().ok()?;
-                        Some((n, rest))
-                    })
-            {
-                return (n.into(), rest);
-            }
+            let (digits, rest) = encoded_value[1..]
+                .split_once('e')
+                .ok_or(anyhow::anyhow!("Unterminated integer: {}", encoded_value))?;
+            Ok((decode_integer(digits)?.into(), rest))
         }
         Some('l') => {
             let mut values = Vec::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (v, remainder) = decode_bencoded_value(rest);
+            let mut rest = &encoded_value[1..];
+            while !rest.starts_with('e') {
+                if rest.is_empty() {
+                    return Err(anyhow::anyhow!("Unterminated list"));
+                }
+                let (v, remainder) = decode_bencoded_value(rest)?;
                 values.push(v);
                 rest = remainder;
             }
-            return (values.into(), &rest[1..]);
+            Ok((values.into(), &rest[1..]))
         }
         Some('d') => {
             let mut dict = serde_json::Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (k, remainder) = decode_bencoded_value(rest);
+            let mut rest = &encoded_value[1..];
+            while !rest.starts_with('e') {
+                if rest.is_empty() {
+                    return Err(anyhow::anyhow!("Unterminated dictionary"));
+                }
+                let (k, remainder) = decode_bencoded_value(rest)?;
                 let k = match k {
                     serde_json::Value::String(k) => k,
-                    k => {
-                        panic!("dict keys must be strings, not {k:?}");
-                    }
+                    k => return Err(anyhow::anyhow!("dict keys must be strings, not {k:?}")),
                 };
-                let (v, remainder) = decode_bencoded_value(remainder);
+                let (v, remainder) = decode_bencoded_value(remainder)?;
                 dict.insert(k, v);
                 rest = remainder;
             }
-            return (dict.into(), &rest[1..]);
+            Ok((dict.into(), &rest[1..]))
+        }
+        _ => Err(anyhow::anyhow!(
+            "Unhandled encoded value: {}",
+            encoded_value
+        )),
+    }
+}
+
+// Integers are plain decimal: no leading zeros, no negative zero and at least one digit,
+// so that every integer has exactly one encoding.
+fn decode_integer(digits: &str) -> anyhow::Result<i64> {
+    let magnitude = digits.strip_prefix('-').unwrap_or(digits);
+
+    if magnitude.is_empty() || !magnitude.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow::anyhow!("Invalid integer: {}", digits));
+    }
+    if magnitude.len() > 1 && magnitude.starts_with('0') {
+        return Err(anyhow::anyhow!("Integer with leading zeros: {}", digits));
+    }
+    if digits == "-0" {
+        return Err(anyhow::anyhow!("Negative zero integer: {}", digits));
+    }
+
+    digits
+        .parse::<i64>()
+        .map_err(|_| anyhow::anyhow!("Integer out of range: {}", digits))
+}
+
+// Encode a decoded value back to canonical bencode, dictionary keys sorted by their raw bytes.
+// Bencode has no booleans, floats or null, so those are rejected.
+pub fn encode_value(value: &serde_json::Value) -> anyhow::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    encode_into(value, &mut encoded)?;
+    Ok(encoded)
+}
+
+fn encode_into(value: &serde_json::Value, encoded: &mut Vec<u8>) -> anyhow::Result<()> {
+    match value {
+        serde_json::Value::Number(n) => {
+            let n = n
+                .as_i64()
+                .ok_or(anyhow::anyhow!("Only integers can be bencoded, got {}", n))?;
+            encoded.extend_from_slice(format!("i{}e", n).as_bytes());
+        }
+        serde_json::Value::String(s) => encode_string(s, encoded),
+        serde_json::Value::Array(values) => {
+            encoded.push(b'l');
+            for v in values {
+                encode_into(v, encoded)?;
+            }
+            encoded.push(b'e');
+        }
+        serde_json::Value::Object(dict) => {
+            let mut entries = dict.iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+
+            encoded.push(b'd');
+            for (k, v) in entries {
+                encode_string(k, encoded);
+                encode_into(v, encoded)?;
+            }
+            encoded.push(b'e');
         }
-        _ => {}
+        v => return Err(anyhow::anyhow!("Value can not be bencoded: {}", v)),
     }
 
-    panic!("Unhandled encoded value: {}", encoded_value)
+    Ok(())
+}
+
+fn encode_string(s: &str, encoded: &mut Vec<u8>) {
+    encoded.extend_from_slice(format!("{}:", s.len()).as_bytes());
+    encoded.extend_from_slice(s.as_bytes());
+}
+
+// The value under `key` in a bencoded dictionary, as the exact bytes it was encoded as.
+pub fn dict_value<'a>(data: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = data.strip_prefix(b"d")?;
+    while !rest.starts_with(b"e") {
+        let (k, after_key) = split_value(rest)?;
+        let (v, after_value) = split_value(after_key)?;
+        // Keys are strings, their content follows the length.
+        let colon = k.iter().position(|&b| b == b':')?;
+        if &k[colon + 1..] == key {
+            return Some(v);
+        }
+        rest = after_value;
+    }
+    None
+}
+
+// Lists and dictionaries nested deeper than this are refused by split_value, rather
+// than overflowing the stack on crafted input.
+const MAX_DEPTH: usize = 64;
+
+// Split the first bencoded value off `data`, without decoding it.
+fn split_value(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    split_nested(data, 0)
+}
+
+fn split_nested(data: &[u8], depth: usize) -> Option<(&[u8], &[u8])> {
+    let len = match data.first()? {
+        b'0'..=b'9' => {
+            let colon = data.iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&data[..colon]).ok()?.parse().ok()?;
+            colon.checked_add(1)?.checked_add(len)?
+        }
+        b'i' => data.iter().position(|&b| b == b'e')? + 1,
+        b'l' | b'd' if depth < MAX_DEPTH => {
+            let mut rest = &data[1..];
+            while !rest.starts_with(b"e") {
+                rest = split_nested(rest, depth + 1)?.1;
+            }
+            data.len() - rest.len() + 1
+        }
+        _ => return None,
+    };
+
+    (len <= data.len()).then(|| data.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encode_round_trip() {
+        let canonical = "d3:bar4:spam3:fooi42e4:listl1:ai-1eee";
+        let (value, rest) = decode_bencoded_value(canonical).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(encode_value(&value).unwrap(), canonical.as_bytes());
+
+        // Keys out of order decode fine, and are sorted when encoding again.
+        let (value, _) = decode_bencoded_value("d3:fooi42e3:bar4:spame").unwrap();
+        assert_eq!(encode_value(&value).unwrap(), b"d3:bar4:spam3:fooi42ee");
+    }
+
+    #[test]
+    fn decode_malformed_values() {
+        for encoded in [
+            "i42", "l1:a", "d1:a1:b", "-3:abc", "l-3:abce", "i-0e", "i042e",
+        ] {
+            assert!(
+                decode_bencoded_value(encoded).is_err(),
+                "{encoded} should not decode"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_integer_is_canonical() {
+        for (digits, n) in [("0", 0), ("-1", -1), ("42", 42)] {
+            assert_eq!(decode_integer(digits).unwrap(), n);
+        }
+        for digits in ["-0", "03", "", "-", "4a2", "99999999999999999999"] {
+            assert!(
+                decode_integer(digits).is_err(),
+                "i{digits}e should not decode"
+            );
+        }
+    }
+
+    #[test]
+    fn split_value_with_huge_string_length() {
+        let data = format!("d1:x{}:ye", usize::MAX);
+        assert_eq!(split_value(data.as_bytes()), None);
+        assert_eq!(dict_value(data.as_bytes(), b"x"), None);
+    }
+
+    #[test]
+    fn split_value_depth_is_capped() {
+        let nested = |depth: usize| format!("{}{}", "l".repeat(depth), "e".repeat(depth));
+
+        let shallow = nested(MAX_DEPTH);
+        assert_eq!(
+            split_value(shallow.as_bytes()),
+            Some((shallow.as_bytes(), &b""[..]))
+        );
+        assert_eq!(split_value(nested(MAX_DEPTH + 1).as_bytes()), None);
+        assert_eq!(split_value(nested(100_000).as_bytes()), None);
+    }
 }
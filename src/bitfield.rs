@@ -0,0 +1,77 @@
+// The pieces a peer has, one bit per piece index.
+//
+// The high bit in the first byte corresponds to piece index 0.
+// Bits that are cleared indicate a missing piece, and set bits indicate a valid and available piece.
+// Spare bits at the end are set to zero.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    // An empty bitfield able to hold `num_pieces` bits.
+    pub fn new(num_pieces: usize) -> Self {
+        Self(vec![0; (num_pieces + 7) / 8])
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.0
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (7 - index % 8)) != 0)
+    }
+
+    // Peers announcing a piece with Have may not have sent a bitfield long enough, so grow as needed.
+    pub fn set(&mut self, index: usize) {
+        if index / 8 >= self.0.len() {
+            self.0.resize(index / 8 + 1, 0);
+        }
+        self.0[index / 8] |= 1 << (7 - index % 8);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    // Indices of the set bits, in ascending order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.0.len() * 8).filter(|&index| self.has(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_msb_first() {
+        let bitfield = Bitfield::from_bytes(vec![0b1010_0000, 0x01]);
+
+        assert!(bitfield.has(0));
+        assert!(!bitfield.has(1));
+        assert!(bitfield.has(2));
+        assert!(!bitfield.has(7));
+        assert!(bitfield.has(15));
+        assert!(!bitfield.has(16));
+        assert_eq!(bitfield.count_ones(), 3);
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), [0, 2, 15]);
+    }
+
+    #[test]
+    fn set_sets_msb_first_and_grows() {
+        let mut bitfield = Bitfield::new(9);
+        bitfield.set(0);
+        bitfield.set(2);
+        bitfield.set(15);
+        assert_eq!(bitfield.as_bytes(), [0b1010_0000, 0x01]);
+
+        bitfield.set(17);
+        assert_eq!(bitfield.as_bytes(), [0b1010_0000, 0x01, 0b0100_0000]);
+        assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), [0, 2, 15, 17]);
+    }
+}
@@ -0,0 +1,183 @@
+// A minimal mainline DHT client (BEP 5), only able to look up peers for an info hash.
+//
+// Nodes are asked for peers with `get_peers` queries sent as bencoded KRPC messages over UDP.
+// A node either answers with peers (`values`) or with nodes closer to the info hash (`nodes`),
+// which are queried next until enough peers were found.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use crate::tracker::Peers;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_bytes::{ByteBuf, Bytes};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+// Well-known nodes to enter the DHT through.
+const BOOTSTRAP_NODES: [&str; 2] = ["router.bittorrent.com:6881", "dht.transmissionbt.com:6881"];
+
+// How many nodes are queried at once in every round.
+const ALPHA: usize = 8;
+
+// Give up after this many rounds of getting closer to the info hash.
+const MAX_ROUNDS: usize = 8;
+
+// How long to wait for the answers of a round.
+const ROUND_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Stop looking once this many peers were found.
+const WANT_PEERS: usize = 50;
+
+// A compact node: 20 bytes node id followed by a compact IPv4 address.
+const COMPACT_NODE_LEN: usize = 26;
+
+// KRPC query: t is the transaction id, y is "q" for queries, q the method name and a its arguments.
+#[derive(Serialize)]
+struct Query<'a> {
+    t: &'a Bytes,
+    y: &'a str,
+    q: &'a str,
+    a: GetPeersArgs<'a>,
+}
+
+#[derive(Serialize)]
+struct GetPeersArgs<'a> {
+    id: &'a Bytes,
+    info_hash: &'a Bytes,
+}
+
+// KRPC message received, y is "r" for responses and "e" for errors.
+#[derive(Deserialize)]
+struct Response {
+    t: ByteBuf,
+    #[serde(default)]
+    r: Option<GetPeersResponse>,
+}
+
+#[derive(Deserialize)]
+struct GetPeersResponse {
+    // nodes: compact nodes closer to the info hash.
+    #[serde(default)]
+    nodes: Option<ByteBuf>,
+    // values: compact peers for the info hash, one per string.
+    #[serde(default)]
+    values: Option<Vec<Peers>>,
+}
+
+// Look up peers for `info_hash` in the DHT.
+pub async fn get_peers(info_hash: [u8; 20]) -> anyhow::Result<Peers> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let node_id: [u8; 20] = rand::thread_rng().gen();
+
+    // Nodes learned so far with their id, bootstrap nodes have no known id.
+    let mut candidates: Vec<(Option<[u8; 20]>, SocketAddr)> = Vec::new();
+    for node in BOOTSTRAP_NODES {
+        match lookup_host(node).await {
+            Ok(addrs) => {
+                candidates.extend(addrs.filter(|addr| addr.is_ipv4()).map(|addr| (None, addr)))
+            }
+            Err(e) => warn!("Failed to resolve DHT node {}: {}", node, e),
+        }
+    }
+    if candidates.is_empty() {
+        return Err(anyhow::anyhow!("No DHT bootstrap node could be resolved"));
+    }
+
+    let mut queried = HashSet::new();
+    let mut peers: Vec<SocketAddr> = Vec::new();
+    let mut transaction: u16 = 0;
+
+    for _ in 0..MAX_ROUNDS {
+        // Ask the closest nodes not asked yet, bootstrap nodes sort first.
+        candidates.sort_by_key(|(id, _)| id.map(|id| distance(&id, &info_hash)));
+        let round = candidates
+            .iter()
+            .filter(|(_, addr)| !queried.contains(addr))
+            .take(ALPHA)
+            .map(|(_, addr)| *addr)
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            break;
+        }
+
+        let mut pending = HashMap::new();
+        for addr in round {
+            queried.insert(addr);
+            transaction = transaction.wrapping_add(1);
+            let t = transaction.to_be_bytes();
+
+            let query = Query {
+                t: Bytes::new(&t),
+                y: "q",
+                q: "get_peers",
+                a: GetPeersArgs {
+                    id: Bytes::new(&node_id),
+                    info_hash: Bytes::new(&info_hash),
+                },
+            };
+            let query = serde_bencode::to_bytes(&query)?;
+            if socket.send_to(&query, addr).await.is_ok() {
+                pending.insert(t.to_vec(), addr);
+            }
+        }
+
+        let deadline = Instant::now() + ROUND_TIMEOUT;
+        let mut buf = [0u8; 2048];
+        while !pending.is_empty() {
+            let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+            else {
+                break;
+            };
+            let Ok((len, from)) = received else {
+                continue;
+            };
+            let Ok(response) = serde_bencode::from_bytes::<Response>(&buf[..len]) else {
+                continue;
+            };
+            // Only accept answers to the queries we sent, from the node we sent them to.
+            if pending.get(response.t.as_slice()) != Some(&from) {
+                continue;
+            }
+            pending.remove(response.t.as_slice());
+
+            let Some(response) = response.r else {
+                continue;
+            };
+            for peer in response.values.into_iter().flatten().flatten() {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            if let Some(nodes) = response.nodes {
+                candidates.extend(nodes.chunks_exact(COMPACT_NODE_LEN).map(parse_node));
+            }
+        }
+
+        if peers.len() >= WANT_PEERS {
+            break;
+        }
+    }
+
+    debug!("Found {} peers in the DHT", peers.len());
+    Ok(Peers(peers))
+}
+
+// Nodes are ordered by the XOR of their id and the info hash, compared as big endian numbers.
+fn distance(id: &[u8; 20], info_hash: &[u8; 20]) -> [u8; 20] {
+    let mut distance = [0u8; 20];
+    for (d, (a, b)) in distance.iter_mut().zip(id.iter().zip(info_hash)) {
+        *d = a ^ b;
+    }
+    distance
+}
+
+fn parse_node(chunk: &[u8]) -> (Option<[u8; 20]>, SocketAddr) {
+    let id: [u8; 20] = chunk[..20].try_into().expect("guaranteed to be length 20");
+    let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+    let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+    (Some(id), SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
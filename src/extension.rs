@@ -0,0 +1,160 @@
+// The extension protocol (BEP 10).
+//
+// Extended messages (message id 20) start with an extended message id byte followed by
+// a bencoded dictionary. Id 0 is the extension handshake, in which each side tells which
+// extensions it supports and the ids it wants to receive their messages under.
+
+use std::collections::BTreeMap;
+
+use crate::peer::{Message, MessageType};
+use crate::tracker::Peers;
+
+use serde::{Deserialize, Serialize};
+
+pub const HANDSHAKE_ID: u8 = 0;
+
+// The id peers send us ut_pex messages under, as announced in our extension handshake.
+pub const UT_PEX_ID: u8 = 1;
+
+// The id peers send us ut_metadata messages under.
+pub const UT_METADATA_ID: u8 = 2;
+
+// An extended message taken apart: the extended message id it was sent under,
+// then the rest of the payload, the bencoded dictionary and any data following it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedMessage {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl ExtendedMessage {
+    // None for any other message type, or an extended message without its id.
+    pub fn from_message(message: &Message) -> Option<Self> {
+        if message.id != MessageType::Extended {
+            return None;
+        }
+
+        let (&id, payload) = message.payload.split_first()?;
+        Some(Self {
+            id,
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn is_handshake(&self) -> bool {
+        self.id == HANDSHAKE_ID
+    }
+}
+
+impl From<ExtendedMessage> for Message {
+    fn from(extended: ExtendedMessage) -> Self {
+        let mut payload = vec![extended.id];
+        payload.extend(extended.payload);
+        Message {
+            id: MessageType::Extended,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    // m: a dictionary from extension names to the extended message ids we receive them under.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    // metadata_size: (optional) size of the info dictionary, sent by peers supporting ut_metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    // Announce the extensions we support.
+    pub fn new() -> Self {
+        Self {
+            m: BTreeMap::from([("ut_pex".to_string(), UT_PEX_ID)]),
+            metadata_size: None,
+        }
+    }
+
+    // Announce ut_metadata only, to fetch the info dictionary of a magnet link.
+    pub fn metadata() -> Self {
+        Self {
+            m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]),
+            metadata_size: None,
+        }
+    }
+
+    // Parse the bencoded dictionary following the extended message id.
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        serde_bencode::from_bytes(payload).ok()
+    }
+
+    // The payload of the extended message, including its extended message id.
+    pub fn as_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![HANDSHAKE_ID];
+        bytes.extend(serde_bencode::to_bytes(self)?);
+        Ok(bytes)
+    }
+}
+
+impl Default for ExtensionHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Metadata exchange (BEP 9): the info dictionary is sent in pieces of 16 KiB,
+// each data message carrying the piece right after its bencoded dictionary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    // msg_type: 0 request, 1 data, 2 reject.
+    pub msg_type: u8,
+    pub piece: usize,
+    // total_size: size of the whole info dictionary, only in data messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub const PIECE_SIZE: usize = 1 << 14;
+
+    pub const REQUEST: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const REJECT: u8 = 2;
+
+    pub fn request(piece: usize) -> Self {
+        Self {
+            msg_type: Self::REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+
+    // The payload of the extended message, sent under the peer's ut_metadata id.
+    pub fn as_bytes(&self, id: u8) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![id];
+        bytes.extend(serde_bencode::to_bytes(self)?);
+        Ok(bytes)
+    }
+
+    // Parse the dictionary at the start of the payload, the piece data following it is left out.
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        serde_bencode::from_bytes(payload).ok()
+    }
+}
+
+// Peer Exchange (BEP 11): peers periodically tell us about peers they are connected to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PexMessage {
+    // added: compact IPv4 peers connected since the last message.
+    // Their flags are under `added.f`, which we do not need.
+    #[serde(default)]
+    pub added: Peers,
+}
+
+impl PexMessage {
+    // Parse the bencoded dictionary following the extended message id.
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        serde_bencode::from_bytes(payload).ok()
+    }
+}
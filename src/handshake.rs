@@ -1,10 +1,53 @@
 // The handshake is a message consisting of the following parts as described in the peer protocol:
 
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
 
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tracing::debug;
+
+// Parse a peer address as given on the command line or in a peers file: ip:port,
+// with IPv6 addresses in brackets, e.g. 127.0.0.1:6881 or [::1]:6881.
+pub fn parse_peer(s: &str) -> anyhow::Result<SocketAddr> {
+    s.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid peer address {:?}, expected ip:port, e.g. 127.0.0.1:6881 or [::1]:6881",
+            s
+        )
+    })
+}
+
+// A connection to a peer, which may be encrypted underneath.
+pub trait PeerIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerIo for T {}
+
+pub type PeerStream = Box<dyn PeerIo>;
+
+// Whether connections we open to peers use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Encryption {
+    // Try an encrypted connection first, falling back to plaintext if the peer does not take it.
+    Prefer,
+    // Only talk to peers over encrypted connections.
+    Require,
+    // Plaintext connections only.
+    #[default]
+    Disable,
+}
+
+static DEFAULT_ENCRYPTION: OnceLock<Encryption> = OnceLock::new();
+
+// Set the encryption of every handshake of the run, before the first one is created.
+pub fn set_default_encryption(encryption: Encryption) {
+    _ = DEFAULT_ENCRYPTION.set(encryption);
+}
 
 // length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
 // the string BitTorrent protocol (19 bytes)
@@ -17,6 +60,28 @@ pub struct Handshake {
     reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
+    // The reserved bytes the remote peer sent, telling which extensions it supports.
+    pub peer_reserved: [u8; 8],
+    pub timeouts: Timeouts,
+    pub encryption: Encryption,
+}
+
+// How long to wait on an unresponsive peer before giving up on it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    // Establishing the TCP connection.
+    pub connect: Duration,
+    // Exchanging the handshake and every peer message after it.
+    pub message: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            message: Duration::from_secs(30),
+        }
+    }
 }
 
 impl Handshake {
@@ -27,6 +92,9 @@ impl Handshake {
             reserved: [0; 8],
             info_hash,
             peer_id,
+            peer_reserved: [0; 8],
+            timeouts: Timeouts::default(),
+            encryption: DEFAULT_ENCRYPTION.get().copied().unwrap_or_default(),
         }
     }
 
@@ -40,20 +108,146 @@ impl Handshake {
         bytes
     }
 
-    pub async fn send(&mut self, peer: &str) -> anyhow::Result<TcpStream> {
-        let peer = peer.parse::<SocketAddrV4>()?;
-        let mut stream = tokio::net::TcpStream::connect(peer).await?;
+    pub async fn send(&mut self, peer: SocketAddr) -> anyhow::Result<PeerStream> {
+        let mut stream: PeerStream = match self.encryption {
+            Encryption::Disable => Box::new(self.connect(peer).await?),
+            Encryption::Require => self.connect_encrypted(peer).await?,
+            Encryption::Prefer => match self.connect_encrypted(peer).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Encrypted connection failed: {}, retrying in plaintext", e);
+                    Box::new(self.connect(peer).await?)
+                }
+            },
+        };
         // TODO: how to change handshake inplace to avoid copy.
         let mut handshake_bytes = self.as_bytes();
-        stream.write_all(&handshake_bytes).await?;
-        stream.read_exact(&mut handshake_bytes).await?;
+        tokio::time::timeout(self.timeouts.message, async {
+            stream.write_all(&handshake_bytes).await?;
+            stream.read_exact(&mut handshake_bytes).await
+        })
+        .await
+        .context("handshake with peer timed out")??;
+
+        self.validate(&handshake_bytes)?;
+
+        self.peer_reserved = handshake_bytes[20..28].try_into().unwrap();
+        self.peer_id = handshake_bytes[48..68].try_into().unwrap();
+
+        Ok(stream)
+    }
+
+    async fn connect(&self, peer: SocketAddr) -> anyhow::Result<TcpStream> {
+        let stream = tokio::time::timeout(self.timeouts.connect, TcpStream::connect(peer))
+            .await
+            .context("connect to peer timed out")??;
+        Ok(stream)
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn connect_encrypted(&self, peer: SocketAddr) -> anyhow::Result<PeerStream> {
+        let stream = self.connect(peer).await?;
+        let stream = tokio::time::timeout(
+            self.timeouts.message,
+            crate::mse::initiate(stream, self.info_hash),
+        )
+        .await
+        .context("encryption handshake timed out")??;
+        Ok(Box::new(stream))
+    }
 
+    #[cfg(not(feature = "encryption"))]
+    async fn connect_encrypted(&self, _peer: SocketAddr) -> anyhow::Result<PeerStream> {
+        Err(anyhow::anyhow!(
+            "Encryption is not supported, build with the encryption feature"
+        ))
+    }
+
+    // Answer a handshake initiated by a remote peer, which must be for our torrent.
+    pub async fn accept(&mut self, stream: &mut TcpStream) -> anyhow::Result<()> {
+        let mut handshake_bytes = [0u8; 68];
+        tokio::time::timeout(
+            self.timeouts.message,
+            stream.read_exact(&mut handshake_bytes),
+        )
+        .await
+        .context("handshake from peer timed out")??;
+
+        self.validate(&handshake_bytes)?;
+
+        stream.write_all(&self.as_bytes()).await?;
+
+        self.peer_reserved = handshake_bytes[20..28].try_into().unwrap();
+        self.peer_id = handshake_bytes[48..68].try_into().unwrap();
+
+        Ok(())
+    }
+
+    // Advertise support for the extension protocol (BEP 10).
+    pub fn enable_extensions(&mut self) {
+        self.reserved[5] |= 0x10;
+    }
+
+    // Check the handshake a peer sent speaks our protocol and is for our torrent.
+    fn validate(&self, handshake_bytes: &[u8; 68]) -> anyhow::Result<()> {
+        if handshake_bytes[0] != self.length {
+            return Err(anyhow::anyhow!(
+                "Unexpected protocol length {} from handshake",
+                handshake_bytes[0]
+            ));
+        }
+        if handshake_bytes[1..20] != self.protocol {
+            return Err(anyhow::anyhow!(
+                "Unexpected protocol {:?} from handshake",
+                String::from_utf8_lossy(&handshake_bytes[1..20])
+            ));
+        }
         if handshake_bytes[28..48] != self.info_hash {
             return Err(anyhow::anyhow!("Mismatched info hash from handshake"));
         }
 
-        self.peer_id = handshake_bytes[48..68].try_into().unwrap();
+        Ok(())
+    }
 
-        Ok(stream)
+    // Extension protocol (BEP 10): bit 20 counted from the right, reserved[5] & 0x10.
+    pub fn supports_extensions(&self) -> bool {
+        self.peer_reserved[5] & 0x10 != 0
+    }
+
+    // DHT (BEP 5): the last bit, reserved[7] & 0x01.
+    pub fn supports_dht(&self) -> bool {
+        self.peer_reserved[7] & 0x01 != 0
+    }
+
+    // Fast Extension (BEP 6): the third least significant bit, reserved[7] & 0x04.
+    pub fn supports_fast(&self) -> bool {
+        self.peer_reserved[7] & 0x04 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv4_peer() {
+        let peer = parse_peer("127.0.0.1:6881").unwrap();
+        assert!(peer.is_ipv4());
+        assert_eq!(peer.to_string(), "127.0.0.1:6881");
+    }
+
+    #[test]
+    fn parse_ipv6_peer() {
+        let peer = parse_peer("[::1]:6881").unwrap();
+        assert!(peer.is_ipv6());
+        assert_eq!(peer.port(), 6881);
+        assert_eq!(peer.to_string(), "[::1]:6881");
+    }
+
+    #[test]
+    fn parse_invalid_peers() {
+        for peer in ["::1:6881", "127.0.0.1", "127.0.0.1:port", ""] {
+            assert!(parse_peer(peer).is_err(), "{}", peer);
+        }
     }
 }
@@ -1,4 +1,25 @@
 pub mod bencode;
+pub mod bitfield;
+pub mod dht;
+pub mod extension;
 pub mod handshake;
+pub mod magnet;
+pub mod metadata;
+#[cfg(feature = "encryption")]
+pub mod mse;
+pub mod peer;
+pub mod peer_id;
+pub mod picker;
+pub mod pool;
+pub mod progress;
+pub mod rate;
+pub mod report;
+pub mod seed;
+pub mod superseed;
 pub mod torrent;
 pub mod tracker;
+pub mod unchoke;
+pub mod verify;
+pub mod webseed;
+pub mod worker;
+pub mod writer;
@@ -0,0 +1,99 @@
+// Magnet links (BEP 9) identify a torrent by its info hash alone, e.g.
+// magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker url>
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // dn: (optional) a display name until the info dictionary is known.
+    pub name: Option<String>,
+    // tr: (optional) tracker URLs, there may be several.
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> anyhow::Result<Self> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .ok_or(anyhow::anyhow!("Not a magnet link: {}", link))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "xt" => {
+                    let hash = value
+                        .strip_prefix("urn:btih:")
+                        .ok_or(anyhow::anyhow!("Unsupported exact topic: {}", value))?;
+                    info_hash = Some(parse_info_hash(hash)?);
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                // Anything else (x.pe, ws, ...) is not used.
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(anyhow::anyhow!("Magnet link has no info hash"))?,
+            name,
+            trackers,
+        })
+    }
+}
+
+// The info hash is either 40 hex digits or 32 base32 characters.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash)?,
+        32 => base32_decode(hash)?,
+        len => return Err(anyhow::anyhow!("Invalid info hash length {}", len)),
+    };
+
+    Ok(bytes.try_into().expect("guaranteed to be length 20"))
+}
+
+// RFC 4648 base32 without padding, as 32 characters always make whole bytes.
+fn base32_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in s.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            c => return Err(anyhow::anyhow!("Invalid base32 character {:?}", c)),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+// Undo the URL encoding of a query value, where `+` also stands for a space.
+fn percent_decode(s: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().unwrap_or(0), iter.next().unwrap_or(0)];
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(anyhow::anyhow!("Invalid percent encoding in {}", s))?;
+                bytes.push(decoded);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
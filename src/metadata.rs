@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+
+use crate::extension::{
+    ExtendedMessage, ExtensionHandshake, MetadataMessage, HANDSHAKE_ID, UT_METADATA_ID,
+};
+use crate::handshake::{Handshake, PeerStream, Timeouts};
+use crate::peer::{Message, MessageFrame, MessageType};
+
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+// Refuse info dictionaries larger than this, whatever size the peer claims.
+const MAX_METADATA_SIZE: usize = 1 << 24;
+
+// Fetch the info dictionary of the torrent from a peer over ut_metadata (BEP 9),
+// checking it hashes to the info hash we asked for. The bytes are returned as received,
+// see Torrent::from_info_bytes.
+#[tracing::instrument(name = "peer", skip(info_hash, peer_id, timeouts))]
+pub async fn fetch_info(
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    timeouts: Timeouts,
+) -> anyhow::Result<Vec<u8>> {
+    let mut handshake = Handshake::new(info_hash, peer_id);
+    handshake.timeouts = timeouts;
+    handshake.enable_extensions();
+    let stream = handshake.send(peer).await?;
+    if !handshake.supports_extensions() {
+        return Err(anyhow::anyhow!(
+            "Peer does not support the extension protocol"
+        ));
+    }
+
+    let mut frame = Framed::new(stream, MessageFrame);
+    frame
+        .send(Message {
+            id: MessageType::Extended,
+            payload: ExtensionHandshake::metadata().as_bytes()?,
+        })
+        .await
+        .context("send extension handshake")?;
+
+    // The peer's extension handshake tells the id to send our requests under and the size.
+    let payload = next_extended(&mut frame, timeouts, HANDSHAKE_ID).await?;
+    let handshake = ExtensionHandshake::from_payload(&payload)
+        .ok_or(anyhow::anyhow!("Invalid extension handshake from peer"))?;
+
+    let metadata_id = match handshake.m.get("ut_metadata") {
+        Some(&id) if id != 0 => id,
+        _ => return Err(anyhow::anyhow!("Peer does not support ut_metadata")),
+    };
+    let metadata_size = handshake
+        .metadata_size
+        .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+        .ok_or(anyhow::anyhow!("Invalid metadata size from peer"))?;
+    debug!("Fetching {} bytes of metadata", metadata_size);
+
+    let num_pieces =
+        (metadata_size + (MetadataMessage::PIECE_SIZE - 1)) / MetadataMessage::PIECE_SIZE;
+    let mut metadata = Vec::with_capacity(metadata_size);
+    for piece in 0..num_pieces {
+        frame
+            .send(Message {
+                id: MessageType::Extended,
+                payload: MetadataMessage::request(piece).as_bytes(metadata_id)?,
+            })
+            .await
+            .context("send metadata request")?;
+
+        let piece_size =
+            (metadata_size - piece * MetadataMessage::PIECE_SIZE).min(MetadataMessage::PIECE_SIZE);
+        let payload = next_extended(&mut frame, timeouts, UT_METADATA_ID).await?;
+        let message = MetadataMessage::from_payload(&payload)
+            .ok_or(anyhow::anyhow!("Invalid metadata message from peer"))?;
+
+        match message.msg_type {
+            MetadataMessage::DATA if message.piece == piece && payload.len() > piece_size => {
+                metadata.extend_from_slice(&payload[payload.len() - piece_size..]);
+            }
+            MetadataMessage::REJECT => {
+                return Err(anyhow::anyhow!("Peer rejected metadata piece {}", piece))
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unexpected metadata message for piece {}",
+                    piece
+                ))
+            }
+        }
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    if hash != info_hash {
+        return Err(anyhow::anyhow!("Metadata does not match the info hash"));
+    }
+
+    Ok(metadata)
+}
+
+// Wait for the next extended message under `id`, skipping everything else the peer sends.
+async fn next_extended(
+    frame: &mut Framed<PeerStream, MessageFrame>,
+    timeouts: Timeouts,
+    id: u8,
+) -> anyhow::Result<Vec<u8>> {
+    loop {
+        let message = tokio::time::timeout(timeouts.message, frame.next())
+            .await
+            .context("peer message timed out")?
+            .ok_or(anyhow::anyhow!("Peer closed while fetching metadata"))?
+            .context("invalid message while fetching metadata")?;
+
+        match ExtendedMessage::from_message(&message) {
+            Some(extended) if extended.id == id => return Ok(extended.payload),
+            _ => continue,
+        }
+    }
+}
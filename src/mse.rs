@@ -0,0 +1,382 @@
+// Message Stream Encryption (MSE/PE), the obfuscation most clients support to get past
+// traffic shaping of plaintext BitTorrent.
+//
+// A Diffie-Hellman exchange gives both sides a shared secret S, from which they derive
+// one RC4 key per direction together with the info hash. Everything after the exchange,
+// starting with the regular BitTorrent handshake, goes through RC4.
+//
+// Only the initiating side is implemented, for connections we open to peers.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::Context as _;
+use num_bigint::BigUint;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// The 768-bit prime P of the key exchange, with generator 2.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_SIZE: usize = 96;
+// Random padding after the public key, and the most the other side may send.
+const MAX_PAD: usize = 512;
+// The verification constant, 8 zero bytes, whose encrypted form marks where the
+// encrypted part of the other side's answer starts.
+const VC: [u8; 8] = [0; 8];
+// crypto_provide / crypto_select bits: we only ever offer full RC4 encryption.
+const CRYPTO_RC4: u32 = 0x02;
+
+// Run the key exchange on a freshly connected stream for the torrent `info_hash`,
+// returning the stream to speak the BitTorrent protocol over.
+pub async fn initiate<S>(mut stream: S, info_hash: [u8; 20]) -> anyhow::Result<Rc4Stream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The private key is a secret, so it comes from the OS rather than a userspace generator.
+    let mut private = [0u8; 20];
+    OsRng.fill_bytes(&mut private);
+    let public = public_key(&private);
+
+    // 1. A->B: Ya, PadA
+    let mut message = public.to_vec();
+    message.extend(random_pad());
+    stream
+        .write_all(&message)
+        .await
+        .context("send public key")?;
+
+    // 2. B->A: Yb, PadB (read as part of the sync below)
+    let mut remote = [0u8; KEY_SIZE];
+    stream
+        .read_exact(&mut remote)
+        .await
+        .context("read peer public key")?;
+    let secret = shared_secret(&remote, &private);
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //    ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    // The torrent's info hash is SKEY, no PadC nor initial payload are sent.
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+    let mut provide = VC.to_vec();
+    provide.extend(CRYPTO_RC4.to_be_bytes());
+    provide.extend(0u16.to_be_bytes());
+    provide.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut provide);
+    message.extend(provide);
+    stream
+        .write_all(&message)
+        .await
+        .context("send crypto offer")?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    // PadB has no length, so look for the encrypted VC right after it.
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    let mut window = Vec::with_capacity(MAX_PAD + vc.len());
+    while !window.ends_with(&vc) {
+        if window.len() == MAX_PAD + vc.len() {
+            return Err(anyhow::anyhow!(
+                "Peer did not answer the encryption handshake"
+            ));
+        }
+        window.push(stream.read_u8().await.context("read peer padding")?);
+    }
+
+    let mut select = [0u8; 6];
+    stream
+        .read_exact(&mut select)
+        .await
+        .context("read crypto select")?;
+    decrypt.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[..4].try_into().expect("4 bytes"));
+    if crypto_select != CRYPTO_RC4 {
+        return Err(anyhow::anyhow!(
+            "Peer selected unsupported crypto method {:#x}",
+            crypto_select
+        ));
+    }
+
+    let pad_len = u16::from_be_bytes(select[4..].try_into().expect("2 bytes")) as usize;
+    if pad_len > MAX_PAD {
+        return Err(anyhow::anyhow!("Padding of {} bytes from peer", pad_len));
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream
+        .read_exact(&mut pad)
+        .await
+        .context("read peer padding")?;
+    decrypt.apply(&mut pad);
+
+    Ok(Rc4Stream {
+        inner: stream,
+        decrypt,
+        encrypt,
+        pending: Vec::new(),
+        written: 0,
+    })
+}
+
+// Our public key 2^private mod P, as sent on the wire.
+fn public_key(private: &[u8]) -> [u8; KEY_SIZE] {
+    to_key_bytes(&BigUint::from(2u32).modpow(&BigUint::from_bytes_be(private), &prime()))
+}
+
+// The secret S = remote^private mod P both sides end up with.
+fn shared_secret(remote: &[u8; KEY_SIZE], private: &[u8]) -> [u8; KEY_SIZE] {
+    to_key_bytes(&BigUint::from_bytes_be(remote).modpow(&BigUint::from_bytes_be(private), &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).expect("valid prime")
+}
+
+// A big-endian number as the fixed 96 bytes exchanged on the wire.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_PAD + 1);
+    (0..len).map(|_| rand::random::<u8>()).collect()
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// The RC4 stream cipher, with the first 1024 bytes of keystream discarded as MSE requires.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::with_key(key);
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    // Plain RC4, keystream from the first byte.
+    fn with_key(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+// A stream encrypted in both directions once the key exchange is done.
+pub struct Rc4Stream<S> {
+    inner: S,
+    decrypt: Rc4,
+    encrypt: Rc4,
+    // Encrypted bytes accepted from the caller but not written yet: the keystream has
+    // moved past them, so they must go out as they are.
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S: AsyncWrite + Unpin> Rc4Stream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rc4Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.decrypt.apply(&mut buf.filled_mut()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rc4Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+
+        this.pending.extend_from_slice(data);
+        this.encrypt.apply(&mut this.pending);
+        // Start writing right away, whatever is left goes out on the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_known_vectors() {
+        for (key, plaintext, ciphertext) in [
+            ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+            ("Wiki", "pedia", "1021bf0420"),
+            ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ] {
+            let mut data = plaintext.as_bytes().to_vec();
+            Rc4::with_key(key.as_bytes()).apply(&mut data);
+            assert_eq!(hex::encode(data), ciphertext);
+        }
+    }
+
+    #[test]
+    fn public_key_known_value() {
+        assert_eq!(
+            hex::encode(public_key(&[0x11; 20])),
+            "3476dc8d123697fcc69e9121618a08514ffa536506df209ce956ad3cf819f76f\
+             3fcebc46f4e8be364ec8f1445b9dbf8a9528ca7bfbc89ad13f28137640a2cfd1\
+             055d55d5cc6676d18b410e086631309e95fa7b0dae2b8379186b17ad3472c755"
+        );
+    }
+
+    #[test]
+    fn key_exchange_agrees() {
+        let (a, b) = ([0x11u8; 20], [0xeeu8; 20]);
+        let secret_a = shared_secret(&public_key(&b), &a);
+        let secret_b = shared_secret(&public_key(&a), &b);
+        assert_eq!(secret_a, secret_b);
+        assert_ne!(secret_a, [0; KEY_SIZE]);
+    }
+
+    // The receiving side of the exchange, as a peer accepting our connection would run it.
+    async fn respond<S>(mut stream: S, info_hash: [u8; 20]) -> (S, Rc4, Rc4)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let private = [0x42u8; 20];
+        let mut remote = [0u8; KEY_SIZE];
+        stream.read_exact(&mut remote).await.unwrap();
+        let mut message = public_key(&private).to_vec();
+        message.extend(random_pad());
+        stream.write_all(&message).await.unwrap();
+        let secret = shared_secret(&remote, &private);
+
+        // Skip PadA up to HASH('req1', S).
+        let req1 = hash(&[b"req1", &secret]);
+        let mut window = Vec::new();
+        while !window.ends_with(&req1) {
+            window.push(stream.read_u8().await.unwrap());
+        }
+        let mut skey = [0u8; 20];
+        stream.read_exact(&mut skey).await.unwrap();
+        let req3 = hash(&[b"req3", &secret]);
+        let skey: Vec<u8> = skey.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+        assert_eq!(skey, hash(&[b"req2", &info_hash]));
+
+        let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+        let mut provide = [0u8; 16];
+        stream.read_exact(&mut provide).await.unwrap();
+        decrypt.apply(&mut provide);
+        assert_eq!(provide[..8], VC);
+        assert_eq!(provide[8..12], CRYPTO_RC4.to_be_bytes());
+        assert_eq!(provide[12..], [0; 4]);
+
+        let mut select = VC.to_vec();
+        select.extend(CRYPTO_RC4.to_be_bytes());
+        select.extend(2u16.to_be_bytes());
+        select.extend([0xaa, 0xbb]);
+        encrypt.apply(&mut select);
+        stream.write_all(&select).await.unwrap();
+
+        (stream, decrypt, encrypt)
+    }
+
+    #[tokio::test]
+    async fn initiate_against_responder() {
+        let info_hash = [7u8; 20];
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(respond(theirs, info_hash));
+
+        let mut stream = initiate(ours, info_hash).await.unwrap();
+        let (mut theirs, mut decrypt, mut encrypt) = responder.await.unwrap();
+
+        // Both directions go through RC4 from here on.
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut hello = [0u8; 5];
+        theirs.read_exact(&mut hello).await.unwrap();
+        assert_ne!(&hello, b"hello");
+        decrypt.apply(&mut hello);
+        assert_eq!(&hello, b"hello");
+
+        let mut answer = b"world".to_vec();
+        encrypt.apply(&mut answer);
+        theirs.write_all(&answer).await.unwrap();
+        let mut world = [0u8; 5];
+        stream.read_exact(&mut world).await.unwrap();
+        assert_eq!(&world, b"world");
+    }
+}
@@ -17,19 +17,66 @@ use tokio_util::codec::Encoder;
 // 6 - request
 // 7 - piece
 // 8 - cancel
+// 14 - have all (Fast Extension)
+// 15 - have none (Fast Extension)
+// 20 - extended (Extension Protocol)
 // 'choke', 'unchoke', 'interested', and 'not interested' have no payload.
+//
+// Any other id is kept as Unknown, so extensions we do not implement do not break the connection.
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MessageType {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotIntereted = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
+    Choke,
+    Unchoke,
+    Interested,
+    NotIntereted,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    HaveAll,
+    HaveNone,
+    Extended,
+    Unknown(u8),
+}
+
+impl MessageType {
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0 => MessageType::Choke,
+            1 => MessageType::Unchoke,
+            2 => MessageType::Interested,
+            3 => MessageType::NotIntereted,
+            4 => MessageType::Have,
+            5 => MessageType::Bitfield,
+            6 => MessageType::Request,
+            7 => MessageType::Piece,
+            8 => MessageType::Cancel,
+            14 => MessageType::HaveAll,
+            15 => MessageType::HaveNone,
+            20 => MessageType::Extended,
+            id => MessageType::Unknown(id),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            MessageType::Choke => 0,
+            MessageType::Unchoke => 1,
+            MessageType::Interested => 2,
+            MessageType::NotIntereted => 3,
+            MessageType::Have => 4,
+            MessageType::Bitfield => 5,
+            MessageType::Request => 6,
+            MessageType::Piece => 7,
+            MessageType::Cancel => 8,
+            MessageType::HaveAll => 14,
+            MessageType::HaveNone => 15,
+            MessageType::Extended => 20,
+            MessageType::Unknown(id) => *id,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,8 +86,14 @@ pub struct Message {
 }
 
 impl Message {
+    // Overall frame cap, generous enough for the bitfield of a torrent with millions of pieces.
+    pub const MAX: usize = 1 << 20;
+
     // All current implementations use 2^14 (16 kiB), and close connections which request an amount greater than that.
-    pub const MAX: usize = 1 << 16;
+    pub const MAX_BLOCK: usize = 1 << 14;
+
+    // A piece message carries its index and begin (4 bytes each) ahead of the block.
+    pub const MAX_PIECE: usize = 1 + 8 + Message::MAX_BLOCK;
 }
 pub struct MessageFrame;
 
@@ -79,6 +132,14 @@ impl Decoder for MessageFrame {
             ));
         }
 
+        // We never request more than a block, so a bigger piece is never expected.
+        if src[4] == MessageType::Piece.id() && length > Message::MAX_PIECE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Piece frame of length {} is too large to decode.", length),
+            ));
+        }
+
         if src.len() < 4 + length {
             // The full string has not yet arrived.
             //
@@ -92,23 +153,7 @@ impl Decoder for MessageFrame {
         }
 
         // All non-keepalive messages start with a single byte which gives their type.
-        let msg_type = match src[4] {
-            0 => MessageType::Choke,
-            1 => MessageType::Unchoke,
-            2 => MessageType::Interested,
-            3 => MessageType::NotIntereted,
-            4 => MessageType::Have,
-            5 => MessageType::Bitfield,
-            6 => MessageType::Request,
-            7 => MessageType::Piece,
-            8 => MessageType::Cancel,
-            msg_type => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unkown message type {}.", msg_type),
-                ))
-            }
-        };
+        let msg_type = MessageType::from_id(src[4]);
 
         let data = if src.len() > 5 {
             src[5..4 + length].to_vec()
@@ -140,19 +185,57 @@ impl Encoder<Message> for MessageFrame {
 
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_le_bytes(item.payload.len() as u32 + 1);
+        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
 
         // Reserve space in the buffer.
         dst.reserve(4 /* length */ + 1 /* tag */ + item.payload.len());
 
         // Write the length and string to the buffer.
         dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.id as u8);
+        dst.put_u8(item.id.id());
         dst.extend_from_slice(&item.payload);
         Ok(())
     }
 }
 
+// keep-alive: <len=0000>
+//
+// Sent on otherwise idle connections so the peer does not drop us, it has no id and no payload.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive;
+
+impl Encoder<KeepAlive> for MessageFrame {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _item: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&0u32.to_be_bytes());
+        Ok(())
+    }
+}
+
+// have: <len=0005><id=4><piece index>
+//
+// Announces a piece the sender completed and verified, the index is a big-endian u32.
+#[derive(Debug, Clone, Copy)]
+pub struct Have {
+    pub index: u32,
+}
+
+impl Have {
+    pub fn load_from_payload(data: &[u8]) -> Option<Self> {
+        Some(Have {
+            index: u32::from_be_bytes(data.try_into().ok()?),
+        })
+    }
+
+    pub fn as_bytes(&self) -> [u8; 4] {
+        self.index.to_be_bytes()
+    }
+}
+
+// request: <len=0013><id=6><index><begin><length>
+//
+// Requests a block of a piece, all three fields are big-endian u32.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Request {
@@ -162,6 +245,18 @@ pub struct Request {
 }
 
 impl Request {
+    pub fn load_from_payload(data: &[u8]) -> Option<Self> {
+        if data.len() != size_of::<Self>() {
+            return None;
+        }
+
+        Some(Request {
+            index: u32::from_be_bytes(data[0..4].try_into().ok()?),
+            begin: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            length: u32::from_be_bytes(data[8..12].try_into().ok()?),
+        })
+    }
+
     pub fn as_bytes(&self) -> [u8; size_of::<Self>()] {
         let mut bytes = [0u8; size_of::<Request>()];
         bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
@@ -171,6 +266,44 @@ impl Request {
     }
 }
 
+// cancel: <len=0013><id=8><index><begin><length>
+//
+// Used to cancel a block request, its payload is identical to that of the request message.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Cancel {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Cancel {
+    pub fn load_from_payload(data: &[u8]) -> Option<Self> {
+        Request::load_from_payload(data).map(Self::from)
+    }
+
+    pub fn as_bytes(&self) -> [u8; size_of::<Self>()] {
+        let mut bytes = [0u8; size_of::<Cancel>()];
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+}
+
+impl From<Request> for Cancel {
+    fn from(request: Request) -> Self {
+        Self {
+            index: request.index,
+            begin: request.begin,
+            length: request.length,
+        }
+    }
+}
+
+// piece: <len=0009+X><id=7><index><begin><block>
+//
+// Carries a block of a piece, borrowing the block from the message payload.
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct Piece<'a> {
@@ -183,6 +316,7 @@ impl<'a> Piece<'a> {
     const INDEX_SIZE: usize = std::mem::size_of::<u32>();
     const BEGIN_SIZE: usize = std::mem::size_of::<u32>();
 
+    // None for a payload too short to hold the index and begin.
     pub fn load_from_payload(data: &'a [u8]) -> Option<Self> {
         if data.len() < Self::INDEX_SIZE + Self::BEGIN_SIZE {
             return None;
@@ -205,4 +339,140 @@ impl<'a> Piece<'a> {
             piece,
         })
     }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::INDEX_SIZE + Self::BEGIN_SIZE + self.piece.len());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.begin.to_be_bytes());
+        bytes.extend_from_slice(self.piece);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The length prefix and id of a frame with `payload_len` bytes of payload.
+    fn frame_header(id: MessageType, payload_len: usize) -> BytesMut {
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&(payload_len as u32 + 1).to_be_bytes());
+        src.put_u8(id.id());
+        src
+    }
+
+    // Encode a message into a frame and decode it back.
+    fn round_trip(message: Message) -> (BytesMut, Message) {
+        let mut frame = BytesMut::new();
+        MessageFrame.encode(message, &mut frame).unwrap();
+        let decoded = MessageFrame.decode(&mut frame.clone()).unwrap().unwrap();
+        (frame, decoded)
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let request = Request {
+            index: 5,
+            begin: 16384,
+            length: 16384,
+        };
+        let (frame, message) = round_trip(Message {
+            id: MessageType::Request,
+            payload: request.as_bytes().to_vec(),
+        });
+        assert_eq!(
+            frame[..],
+            [0, 0, 0, 13, 6, 0, 0, 0, 5, 0, 0, 0x40, 0, 0, 0, 0x40, 0]
+        );
+
+        assert_eq!(message.id, MessageType::Request);
+        let loaded = Request::load_from_payload(&message.payload).unwrap();
+        assert_eq!(loaded.index, 5);
+        assert_eq!(loaded.begin, 16384);
+        assert_eq!(loaded.length, 16384);
+
+        assert!(Request::load_from_payload(&message.payload[..11]).is_none());
+        assert!(Request::load_from_payload(&[0; 13]).is_none());
+    }
+
+    #[test]
+    fn cancel_round_trip() {
+        let request = Request {
+            index: 7,
+            begin: 32768,
+            length: 1000,
+        };
+        let cancel = Cancel::from(request);
+        // Only the message id tells a cancel from the request it cancels.
+        assert_eq!(cancel.as_bytes(), request.as_bytes());
+
+        let (frame, message) = round_trip(Message {
+            id: MessageType::Cancel,
+            payload: cancel.as_bytes().to_vec(),
+        });
+        assert_eq!(frame[..5], [0, 0, 0, 13, 8]);
+
+        assert_eq!(message.id, MessageType::Cancel);
+        let loaded = Cancel::load_from_payload(&message.payload).unwrap();
+        assert_eq!(loaded.index, 7);
+        assert_eq!(loaded.begin, 32768);
+        assert_eq!(loaded.length, 1000);
+    }
+
+    #[test]
+    fn piece_payload_round_trip() {
+        let block = [1, 2, 3];
+        let piece = Piece {
+            index: 5,
+            begin: 16384,
+            piece: &block,
+        };
+        let payload = piece.as_bytes();
+        assert_eq!(payload, [0, 0, 0, 5, 0, 0, 0x40, 0, 1, 2, 3]);
+
+        let loaded = Piece::load_from_payload(&payload).unwrap();
+        assert_eq!(loaded.index, 5);
+        assert_eq!(loaded.begin, 16384);
+        assert_eq!(loaded.piece, block);
+
+        // An empty block is still a valid payload.
+        let loaded = Piece::load_from_payload(&payload[..8]).unwrap();
+        assert!(loaded.piece.is_empty());
+    }
+
+    #[test]
+    fn piece_payload_too_short() {
+        for len in 0..8 {
+            assert!(Piece::load_from_payload(&[0; 8][..len]).is_none());
+        }
+    }
+
+    #[test]
+    fn oversized_piece_frame_is_rejected() {
+        // Rejected from the header alone, before the payload arrived.
+        let mut src = frame_header(MessageType::Piece, 8 + Message::MAX_BLOCK + 1);
+        let err = MessageFrame.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut src = frame_header(MessageType::Piece, 8 + Message::MAX_BLOCK);
+        src.extend_from_slice(&[0; 8 + Message::MAX_BLOCK]);
+        let message = MessageFrame.decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.id, MessageType::Piece);
+        assert_eq!(message.payload.len(), 8 + Message::MAX_BLOCK);
+    }
+
+    #[test]
+    fn large_bitfield_frame_is_accepted() {
+        let len = Message::MAX - 1;
+        let mut src = frame_header(MessageType::Bitfield, len);
+        src.extend_from_slice(&vec![0xff; len]);
+
+        let message = MessageFrame.decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.id, MessageType::Bitfield);
+        assert_eq!(message.payload.len(), len);
+        assert!(src.is_empty());
+
+        let mut src = frame_header(MessageType::Bitfield, Message::MAX);
+        assert!(MessageFrame.decode(&mut src).is_err());
+    }
 }
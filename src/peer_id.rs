@@ -0,0 +1,104 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+// Azureus-style client prefix: '-', two characters for the client, four digits for the version, '-'.
+const PREFIX: &[u8; 8] = b"-RS0001-";
+
+// Generate a fresh peer id for this run: the client prefix followed by 12 random bytes.
+// The random part is kept alphanumeric so the id stays printable.
+pub fn generate() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..PREFIX.len()].copy_from_slice(PREFIX);
+
+    let random = rand::thread_rng().sample_iter(Alphanumeric);
+    for (byte, random) in peer_id[PREFIX.len()..].iter_mut().zip(random) {
+        *byte = random;
+    }
+
+    peer_id
+}
+
+// Parse a user supplied peer id, which must be exactly 20 bytes long.
+pub fn parse(peer_id: &str) -> anyhow::Result<[u8; 20]> {
+    peer_id
+        .as_bytes()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Peer id must be 20 bytes long, got {} bytes", peer_id.len()))
+}
+
+// Show a peer id as text when every byte is printable ASCII, in hex otherwise.
+pub fn display(peer_id: &[u8; 20]) -> String {
+    if peer_id.iter().all(|byte| byte.is_ascii_graphic()) {
+        String::from_utf8_lossy(peer_id).into_owned()
+    } else {
+        hex::encode(peer_id)
+    }
+}
+
+// Name the client which generated a peer id, when it follows a known convention.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    // Azureus-style: '-', two characters for the client, four for the version, '-'.
+    if peer_id[0] == b'-' && peer_id[7] == b'-' {
+        let client = match &peer_id[1..3] {
+            b"AZ" => "Vuze",
+            b"BI" => "BiglyBT",
+            b"DE" => "Deluge",
+            b"KT" => "KTorrent",
+            b"LT" => "libtorrent (Rasterbar)",
+            b"lt" => "libTorrent (Rakshasa)",
+            b"qB" => "qBittorrent",
+            b"RS" => "bittorrent-starter-rust",
+            b"TR" => "Transmission",
+            b"UT" => "µTorrent",
+            b"UW" => "µTorrent Web",
+            _ => return None,
+        };
+        let version = peer_id[3..7]
+            .iter()
+            .map(|&byte| (byte as char).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        return Some(format!("{} {}", client, version));
+    }
+
+    // Shad0w-style: one character for the client, up to five for the version, then '-'s.
+    let client = match peer_id[0] {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow's client",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    };
+    let version = peer_id[1..6]
+        .iter()
+        .take_while(|&&byte| byte != b'-')
+        .map(|&byte| shadow_version_digit(byte))
+        .collect::<Option<Vec<_>>>()?;
+    // Make sure the version is terminated by the dashes.
+    if version.is_empty()
+        || peer_id[1 + version.len()..9]
+            .iter()
+            .any(|&byte| byte != b'-')
+    {
+        return None;
+    }
+    let version = version
+        .iter()
+        .map(|digit| digit.to_string())
+        .collect::<Vec<_>>()
+        .join(".");
+    Some(format!("{} {}", client, version))
+}
+
+// Shad0w-style version characters: '0'-'9' are 0-9, 'A'-'Z' 10-35 and 'a'-'z' 36-61.
+fn shadow_version_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'Z' => Some(byte - b'A' + 10),
+        b'a'..=b'z' => Some(byte - b'a' + 36),
+        _ => None,
+    }
+}
@@ -0,0 +1,176 @@
+use std::cmp::Reverse;
+use std::collections::vec_deque::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::bitfield::Bitfield;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickMode {
+    // Hand out pieces in ascending order.
+    Fifo,
+    // Hand out the piece the fewest connected peers have first.
+    RarestFirst,
+}
+
+#[derive(Debug)]
+struct PickerState {
+    // Pieces waiting to be downloaded. Pieces in flight are not in here, so they are never assigned twice.
+    pending: VecDeque<usize>,
+    // How many connected peers have each piece.
+    availability: Vec<usize>,
+    // How many downloads of each piece failed their hash or validation.
+    failures: Vec<usize>,
+    // Pieces of a higher priority are handed out before any of a lower one.
+    priority: Vec<usize>,
+    // The first piece that failed too often to be tried again.
+    given_up: Option<usize>,
+}
+
+// Shared between workers to decide which piece each of them downloads next.
+#[derive(Clone, Debug)]
+pub struct PiecePicker {
+    mode: PickMode,
+    // Corrupt downloads of a piece before it is given up, without a limit it is retried forever.
+    max_failures: Option<usize>,
+    state: Arc<Mutex<PickerState>>,
+    given_up: Arc<Notify>,
+}
+
+impl PiecePicker {
+    pub fn new(pieces: impl IntoIterator<Item = usize>, num_pieces: usize, mode: PickMode) -> Self {
+        let state = PickerState {
+            availability: vec![0; num_pieces],
+            pending: pieces.into_iter().collect::<VecDeque<usize>>(),
+            failures: vec![0; num_pieces],
+            priority: vec![0; num_pieces],
+            given_up: None,
+        };
+
+        Self {
+            mode,
+            max_failures: None,
+            state: Arc::new(Mutex::new(state)),
+            given_up: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn with_max_failures(mut self, max_failures: usize) -> Self {
+        self.max_failures = Some(max_failures);
+        self
+    }
+
+    // The priority of each piece, all 0 by default. The pick mode orders the pieces
+    // within a priority.
+    pub fn with_priorities(self, priority: Vec<usize>) -> Self {
+        self.state.lock().expect("PiecePicker priorities").priority = priority;
+        self
+    }
+
+    // Count the pieces of a newly connected peer.
+    pub fn add_peer(&self, bitfield: &Bitfield) {
+        let mut state = self.state.lock().expect("PiecePicker add peer");
+        for piece in bitfield.iter_ones() {
+            if let Some(count) = state.availability.get_mut(piece) {
+                *count += 1;
+            }
+        }
+    }
+
+    // Forget the pieces of a disconnected peer.
+    pub fn remove_peer(&self, bitfield: &Bitfield) {
+        let mut state = self.state.lock().expect("PiecePicker remove peer");
+        for piece in bitfield.iter_ones() {
+            if let Some(count) = state.availability.get_mut(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    // A connected peer announced a new piece.
+    pub fn add_piece(&self, piece: usize) {
+        let mut state = self.state.lock().expect("PiecePicker add piece");
+        if let Some(count) = state.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    // Take the next pending piece available in the peer's bitfield, leaving the others pending.
+    pub fn take_piece_in(&self, bitfield: &Bitfield) -> Option<usize> {
+        let mut state = self.state.lock().expect("PiecePicker take piece");
+        let candidates = state
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, &piece)| bitfield.has(piece));
+
+        let priority = |piece: usize| Reverse(state.priority.get(piece).copied().unwrap_or(0));
+        let position = match self.mode {
+            PickMode::Fifo => candidates
+                .min_by_key(|(position, &piece)| (priority(piece), *position))
+                .map(|(position, _)| position),
+            // Ties are broken by queue order.
+            PickMode::RarestFirst => candidates
+                .min_by_key(|(position, &piece)| {
+                    (priority(piece), state.availability[piece], *position)
+                })
+                .map(|(position, _)| position),
+        }?;
+
+        state.pending.remove(position)
+    }
+
+    // How many connected peers have each piece.
+    pub fn availability(&self) -> Vec<usize> {
+        self.state
+            .lock()
+            .expect("PiecePicker availability")
+            .availability
+            .clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state
+            .lock()
+            .expect("PiecePicker is empty")
+            .pending
+            .is_empty()
+    }
+
+    // Give back a piece whose download was cut short, e.g. by the connection dropping.
+    pub fn push_piece(&self, piece: usize) {
+        self.state
+            .lock()
+            .expect("PiecePicker push piece")
+            .pending
+            .push_back(piece);
+    }
+
+    // Give back a piece whose data failed its hash or validation, unless it failed too
+    // often already.
+    pub fn fail_piece(&self, piece: usize) {
+        let mut state = self.state.lock().expect("PiecePicker fail piece");
+        let Some(failures) = state.failures.get_mut(piece) else {
+            return;
+        };
+        *failures += 1;
+
+        if self.max_failures.is_some_and(|max| *failures >= max) {
+            state.given_up.get_or_insert(piece);
+            self.given_up.notify_one();
+        } else {
+            state.pending.push_back(piece);
+        }
+    }
+
+    // Wait for a piece to be given up, meaning no peer could deliver a good copy of it.
+    pub async fn given_up(&self) -> usize {
+        loop {
+            if let Some(piece) = self.state.lock().expect("PiecePicker given up").given_up {
+                return piece;
+            }
+            self.given_up.notified().await;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::bitfield::Bitfield;
+use crate::handshake::PeerStream;
+use crate::peer::MessageFrame;
+
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+
+// An unchoked connection to a peer, along with the pieces it has.
+pub struct Connection {
+    pub frame: Framed<PeerStream, MessageFrame>,
+    pub bitfield: Bitfield,
+    // Keeps the peer id marked as connected for as long as the connection is open.
+    pub active: ActivePeer,
+    // When the connection was opened or last sent a keepalive from the pool.
+    pub keepalive_sent: Instant,
+}
+
+// Live connections shared by every clone, keyed by peer address, so a worker picking
+// a peer up again skips the connect and handshake round-trips.
+//
+// A connection is taken out while in use and only put back once the worker is done
+// with it cleanly, so one that errored is dropped rather than handed out again.
+#[derive(Clone, Default)]
+pub struct PeerConnectionPool {
+    connections: Arc<Mutex<HashMap<SocketAddr, Connection>>>,
+}
+
+impl PeerConnectionPool {
+    pub fn take(&self, peer: SocketAddr) -> Option<Connection> {
+        self.connections
+            .lock()
+            .expect("PeerConnectionPool take")
+            .remove(&peer)
+    }
+
+    pub fn put(&self, peer: SocketAddr, connection: Connection) {
+        self.connections
+            .lock()
+            .expect("PeerConnectionPool put")
+            .insert(peer, connection);
+    }
+}
+
+// Peer ids of the clients we are connected to, shared by every clone, so a client
+// reachable under several addresses only gets one connection.
+#[derive(Clone, Default)]
+pub struct ActivePeers {
+    ids: Arc<Mutex<HashSet<[u8; 20]>>>,
+}
+
+impl ActivePeers {
+    // Mark the peer id as connected until the returned guard is dropped,
+    // fails if another connection to the same client is open already.
+    pub fn register(&self, id: [u8; 20]) -> Result<ActivePeer, DuplicatePeer> {
+        let inserted = self.ids.lock().expect("ActivePeers register").insert(id);
+        if !inserted {
+            return Err(DuplicatePeer(id));
+        }
+
+        Ok(ActivePeer {
+            peers: self.clone(),
+            id,
+        })
+    }
+}
+
+pub struct ActivePeer {
+    peers: ActivePeers,
+    id: [u8; 20],
+}
+
+impl Drop for ActivePeer {
+    fn drop(&mut self) {
+        self.peers
+            .ids
+            .lock()
+            .expect("ActivePeer drop")
+            .remove(&self.id);
+    }
+}
+
+// The peer id of a new connection is one we are connected to already.
+#[derive(Debug)]
+pub struct DuplicatePeer(pub [u8; 20]);
+
+impl std::fmt::Display for DuplicatePeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Already connected to peer id {}", hex::encode(self.0))
+    }
+}
+
+impl std::error::Error for DuplicatePeer {}
+
+// Peers that sent pieces failing their hash check, with those pieces, shared by every
+// clone. For the rest of the download a peer is not given a piece it corrupted again,
+// and it is banned once it corrupted MAX_BAD_PIECES of them.
+#[derive(Clone, Default)]
+pub struct BadPeers {
+    pieces: Arc<Mutex<HashMap<SocketAddr, HashSet<usize>>>>,
+}
+
+impl BadPeers {
+    pub const MAX_BAD_PIECES: usize = 3;
+
+    // Record a corrupt piece from the peer, returning whether the peer is now banned.
+    pub fn record(&self, peer: SocketAddr, piece: usize) -> bool {
+        let mut pieces = self.pieces.lock().expect("BadPeers record");
+        let bad = pieces.entry(peer).or_default();
+        bad.insert(piece);
+        bad.len() >= Self::MAX_BAD_PIECES
+    }
+
+    pub fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.pieces
+            .lock()
+            .expect("BadPeers is banned")
+            .get(&peer)
+            .is_some_and(|bad| bad.len() >= Self::MAX_BAD_PIECES)
+    }
+
+    // The pieces of the peer's bitfield it may be given, those it did not corrupt.
+    pub fn allowed<'a>(&self, peer: SocketAddr, bitfield: &'a Bitfield) -> Cow<'a, Bitfield> {
+        let pieces = self.pieces.lock().expect("BadPeers allowed");
+        let Some(bad) = pieces.get(&peer) else {
+            return Cow::Borrowed(bitfield);
+        };
+
+        let mut allowed = Bitfield::new(0);
+        for piece in bitfield.iter_ones().filter(|piece| !bad.contains(piece)) {
+            allowed.set(piece);
+        }
+        Cow::Owned(allowed)
+    }
+}
+
+// The peer sent too many pieces that failed their hash check to be used anymore.
+#[derive(Debug)]
+pub struct BannedPeer(pub SocketAddr);
+
+impl std::fmt::Display for BannedPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Peer {} sent {} corrupt pieces",
+            self.0,
+            BadPeers::MAX_BAD_PIECES
+        )
+    }
+}
+
+impl std::error::Error for BannedPeer {}
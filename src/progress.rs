@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Tracks how much of a download is done and how fast it currently goes.
+pub struct Progress {
+    total_pieces: usize,
+    total_bytes: usize,
+    pieces_done: usize,
+    bytes_done: usize,
+    // Bytes delivered at each point in time within the last WINDOW.
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl Progress {
+    // Speed is averaged over the pieces delivered within this window.
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    pub fn new(total_pieces: usize, total_bytes: usize) -> Self {
+        Self {
+            total_pieces,
+            total_bytes,
+            pieces_done: 0,
+            bytes_done: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    // Count pieces that were already on disk, without affecting the speed.
+    pub fn skip(&mut self, pieces: usize, bytes: usize) {
+        self.pieces_done += pieces;
+        self.bytes_done += bytes;
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.pieces_done += 1;
+        self.bytes_done += bytes;
+        self.recent.push_back((now, bytes));
+
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > Self::WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    // Bytes per second over the window.
+    pub fn speed(&self) -> f64 {
+        let (Some((first, _)), Some((last, _))) = (self.recent.front(), self.recent.back()) else {
+            return 0.0;
+        };
+        // Measure at least a second so a lone piece does not read as arbitrarily fast.
+        let elapsed = last.duration_since(*first).max(Duration::from_secs(1));
+        let bytes: usize = self.recent.iter().map(|(_, bytes)| bytes).sum();
+
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = if self.total_bytes == 0 {
+            100.0
+        } else {
+            self.bytes_done as f64 * 100.0 / self.total_bytes as f64
+        };
+
+        write!(
+            f,
+            "Progress: {:.1}% ({}/{} pieces, {} of {}), {}/s",
+            percent,
+            self.pieces_done,
+            self.total_pieces,
+            format_size(self.bytes_done),
+            format_size(self.total_bytes),
+            format_size(self.speed() as usize)
+        )
+    }
+}
+
+// A byte count in the largest binary unit it reaches, e.g. 1.44 GiB or 512 B.
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.2} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_size_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.00 KiB");
+        assert_eq!(format_size(92063), "89.91 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.50 MiB");
+        assert_eq!(format_size(1024 * 1024 * 1024), "1.00 GiB");
+    }
+}
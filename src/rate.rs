@@ -0,0 +1,96 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// A token bucket shared by every clone, so a cap applies to all workers together.
+// Without a rate it never waits.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+struct Bucket {
+    // Bytes per second.
+    rate: f64,
+    // Tokens may go negative: whoever took them waits until the bucket refilled.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    // `kib_per_sec` of None or zero means unlimited.
+    pub fn new(kib_per_sec: Option<usize>) -> Self {
+        let bucket = kib_per_sec.filter(|&rate| rate > 0).map(|rate| {
+            let rate = (rate * 1024) as f64;
+            Arc::new(Mutex::new(Bucket {
+                rate,
+                // Allow a burst of up to one second of traffic.
+                tokens: rate,
+                last: Instant::now(),
+            }))
+        });
+
+        Self { bucket }
+    }
+
+    // Take `bytes` from the bucket, sleeping until they are covered by the rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock().expect("RateLimiter acquire");
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * bucket.rate;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.rate);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn acquire_waits_for_the_rate() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(Some(1));
+        let start = Instant::now();
+
+        // The first second's worth is the burst, the rest comes at 1 KiB per second.
+        limiter.acquire(1024).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(2048).await;
+        limiter.acquire(1024).await;
+
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(3) && elapsed < Duration::from_millis(3100),
+            "took {elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(None);
+        let start = Instant::now();
+
+        limiter.acquire(usize::MAX).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
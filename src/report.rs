@@ -0,0 +1,64 @@
+use std::net::SocketAddr;
+
+use crate::progress::Progress;
+
+// What a download tells the user as it goes, kept apart from the download itself so
+// that scripts can silence it.
+pub trait Reporter: Send + Sync {
+    // A worker opened a new connection to `peer`.
+    fn peer_connected(&self, peer: SocketAddr);
+    // Piece `piece_i` is on disk, and already counted in `progress`.
+    fn piece_done(&self, piece_i: usize, progress: &Progress);
+    // Every wanted piece of the torrent `name` is in `output`.
+    fn finished(&self, name: &str, output: &str);
+    // For a dry run: `responded` of `num_peers` peers sent their pieces, which together
+    // have each piece `availability[piece_i]` times.
+    fn availability(&self, responded: usize, num_peers: usize, availability: &[usize]);
+    // A seeder is up with `have` of the torrent's `num_pieces` pieces.
+    fn seeding(&self, have: usize, num_pieces: usize);
+}
+
+// Prints the progress after every piece and a final line, to stdout.
+pub struct StdoutReporter;
+
+impl Reporter for StdoutReporter {
+    // Far too many to print, --verbose logs them.
+    fn peer_connected(&self, _peer: SocketAddr) {}
+
+    fn piece_done(&self, _piece_i: usize, progress: &Progress) {
+        println!("{}", progress);
+    }
+
+    fn finished(&self, name: &str, output: &str) {
+        println!("Downloaded {} to {}.", name, output);
+    }
+
+    fn availability(&self, responded: usize, num_peers: usize, availability: &[usize]) {
+        let min = availability.iter().min().copied().unwrap_or(0);
+        let max = availability.iter().max().copied().unwrap_or(0);
+        println!("{} of {} peers responded.", responded, num_peers);
+        println!("Availability: min {}, max {}", min, max);
+        if min > 0 {
+            println!("All {} pieces are available.", availability.len());
+        }
+    }
+
+    fn seeding(&self, have: usize, num_pieces: usize) {
+        println!("Serving {} of {} pieces.", have, num_pieces);
+    }
+}
+
+// Reports nothing, for --quiet and for library users with output of their own.
+pub struct QuietReporter;
+
+impl Reporter for QuietReporter {
+    fn peer_connected(&self, _peer: SocketAddr) {}
+
+    fn piece_done(&self, _piece_i: usize, _progress: &Progress) {}
+
+    fn finished(&self, _name: &str, _output: &str) {}
+
+    fn availability(&self, _responded: usize, _num_peers: usize, _availability: &[usize]) {}
+
+    fn seeding(&self, _have: usize, _num_pieces: usize) {}
+}
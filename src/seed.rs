@@ -0,0 +1,349 @@
+use std::io::SeekFrom;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bitfield::Bitfield;
+use crate::handshake;
+use crate::peer;
+use crate::rate::RateLimiter;
+use crate::report::{QuietReporter, Reporter};
+use crate::superseed::SuperSeeder;
+use crate::torrent::Torrent;
+use crate::unchoke::UnchokeManager;
+
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+use handshake::Handshake;
+use peer::{Have, Message, MessageFrame, MessageType, Piece, Request};
+
+// Serves the pieces of a completed file to peers connecting to us.
+pub struct Seeder {
+    torrent: Arc<Torrent>,
+    file: PathBuf,
+    peer_id: [u8; 20],
+    // The pieces that verified against the torrent, on start or added since.
+    bitfield: Mutex<Bitfield>,
+    // Pieces added while seeding, for every connection to announce to its peer.
+    haves: broadcast::Sender<usize>,
+    // Caps the rate blocks are sent at, across all connected peers.
+    limiter: RateLimiter,
+    // Which of the connected peers are allowed to request.
+    unchoker: Mutex<UnchokeManager>,
+    // Set to offer peers a piece at a time instead of sending them our bitfield.
+    super_seeder: Option<Mutex<SuperSeeder>>,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Seeder {
+    // All current implementations close connections which request more than 16 kiB.
+    const MAX_REQUEST: usize = 1 << 14;
+    // How often the unchoked peers are chosen again from the data sent to them.
+    const ROUND_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub async fn new(
+        torrent: Arc<Torrent>,
+        file: PathBuf,
+        peer_id: [u8; 20],
+    ) -> anyhow::Result<Self> {
+        if torrent.info.file_length().is_none() {
+            return Err(anyhow::anyhow!("MultiFile is unsupported"));
+        }
+
+        // Only advertise pieces whose data actually matches the torrent.
+        let num_pieces = torrent.info.pieces.num_pieces();
+        let mut bitfield = Bitfield::new(num_pieces);
+        let mut data = File::open(&file).await.context("open file to seed")?;
+        let mut piece = vec![0u8; torrent.info.plength];
+
+        for piece_i in 0..num_pieces {
+            let piece_size = torrent.info.piece_size(piece_i);
+            if data.read_exact(&mut piece[..piece_size]).await.is_err() {
+                break;
+            }
+
+            let hash: [u8; 20] = Sha1::digest(&piece[..piece_size]).into();
+            if hash == torrent.info.pieces[piece_i] {
+                bitfield.set(piece_i);
+            }
+        }
+
+        // Every piece is added at most once, so a connection can never fall behind.
+        let (haves, _) = broadcast::channel(num_pieces.max(1));
+
+        Ok(Self {
+            torrent,
+            file,
+            peer_id,
+            bitfield: Mutex::new(bitfield),
+            haves,
+            limiter: RateLimiter::default(),
+            unchoker: Mutex::new(UnchokeManager::default()),
+            super_seeder: None,
+            reporter: Arc::new(QuietReporter),
+        })
+    }
+
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn with_reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    // Super-seed, for the initial seeder of a torrent: connected peers only ever learn
+    // about the piece or two they were offered.
+    pub fn with_super_seed(mut self) -> Self {
+        let num_pieces = self.torrent.info.pieces.num_pieces();
+        let ours = self.bitfield().clone();
+        self.super_seeder = Some(Mutex::new(SuperSeeder::new(ours, num_pieces)));
+        self
+    }
+
+    // Serve a piece written to the file and verified since the seeder started, and
+    // announce it to the connected peers.
+    pub fn add_piece(&self, piece_i: usize) {
+        let mut bitfield = self.bitfield();
+        if piece_i >= self.torrent.info.pieces.num_pieces() || bitfield.has(piece_i) {
+            return;
+        }
+        bitfield.set(piece_i);
+        if let Some(mut super_seeder) = self.super_seeder() {
+            super_seeder.add_piece(piece_i);
+            return;
+        }
+        // Nobody to tell when no peer is connected.
+        _ = self.haves.send(piece_i);
+    }
+
+    pub async fn run(self: Arc<Self>, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+            .await
+            .context("bind seeding port")?;
+        self.reporter.seeding(
+            self.bitfield().count_ones(),
+            self.torrent.info.pieces.num_pieces(),
+        );
+        self.listen(listener).await
+    }
+
+    // Serve every peer connecting to the listener. The connections are dropped along
+    // with the returned future.
+    pub async fn listen(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        let seeder = self;
+        let mut tasks = JoinSet::new();
+
+        let rounds = seeder.clone();
+        tasks.spawn(async move {
+            let mut interval = tokio::time::interval(Self::ROUND_INTERVAL);
+            loop {
+                interval.tick().await;
+                rounds.unchoker().next_round(Instant::now());
+            }
+        });
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let seeder = seeder.clone();
+            // Forget the connections that are over.
+            while tasks.try_join_next().is_some() {}
+
+            tasks.spawn(async move {
+                if let Err(e) = seeder.serve(stream, peer).await {
+                    debug!("Peer {} disconnected: {}", peer, e);
+                }
+                seeder.unchoker().remove(peer, Instant::now());
+                if let Some(mut super_seeder) = seeder.super_seeder() {
+                    super_seeder.remove(peer);
+                }
+            });
+        }
+    }
+
+    fn unchoker(&self) -> std::sync::MutexGuard<'_, UnchokeManager> {
+        self.unchoker.lock().expect("Seeder unchoker")
+    }
+
+    fn bitfield(&self) -> std::sync::MutexGuard<'_, Bitfield> {
+        self.bitfield.lock().expect("Seeder bitfield")
+    }
+
+    fn super_seeder(&self) -> Option<std::sync::MutexGuard<'_, SuperSeeder>> {
+        let super_seeder = self.super_seeder.as_ref()?;
+        Some(super_seeder.lock().expect("Seeder super seeder"))
+    }
+
+    // Whether the peer may request the piece: it must be one we have and, when
+    // super-seeding, one the peer was offered.
+    fn serves(&self, peer: SocketAddr, piece_i: usize) -> bool {
+        self.bitfield().has(piece_i)
+            && self
+                .super_seeder()
+                .map_or(true, |super_seeder| super_seeder.was_offered(peer, piece_i))
+    }
+
+    #[tracing::instrument(name = "peer", skip(self, stream))]
+    async fn serve(&self, mut stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+        let mut handshake = Handshake::new(self.torrent.info_hash()?, self.peer_id);
+        handshake.accept(&mut stream).await?;
+        debug!("Peer {} connected", peer);
+
+        // Subscribe first, so a piece added meanwhile is either in the bitfield or announced.
+        // A super-seeding peer looks like it has nothing, until it offers pieces.
+        let mut haves = self.haves.subscribe();
+        let mut offers = self.super_seeder().map(|mut s| s.register(peer));
+        let bitfield = match offers {
+            Some(_) => Bitfield::new(self.torrent.info.pieces.num_pieces()),
+            None => self.bitfield().clone(),
+        };
+        let bitfield = bitfield.as_bytes().to_vec();
+        let mut frame = Framed::new(stream, MessageFrame);
+        frame
+            .send(Message {
+                id: MessageType::Bitfield,
+                payload: bitfield,
+            })
+            .await
+            .context("send bitfield message")?;
+
+        let mut file = File::open(&self.file).await?;
+        let mut choked = true;
+        let mut unchoke = self.unchoker().register(peer);
+
+        loop {
+            let message = tokio::select! {
+                message = frame.next() => match message {
+                    Some(message) => message.context("invalid message")?,
+                    None => break,
+                },
+                have = haves.recv() => {
+                    let piece_i = match have {
+                        Ok(piece_i) => piece_i,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    frame
+                        .send(Message {
+                            id: MessageType::Have,
+                            payload: Have { index: piece_i as u32 }.as_bytes().to_vec(),
+                        })
+                        .await
+                        .context("send have message")?;
+                    continue;
+                }
+                Some(piece_i) = next_offer(&mut offers) => {
+                    frame
+                        .send(Message {
+                            id: MessageType::Have,
+                            payload: Have { index: piece_i as u32 }.as_bytes().to_vec(),
+                        })
+                        .await
+                        .context("send have message")?;
+                    continue;
+                }
+                changed = unchoke.changed() => {
+                    changed.context("unchoker dropped")?;
+                    let unchoked = *unchoke.borrow_and_update();
+                    if unchoked != choked {
+                        continue;
+                    }
+                    choked = !unchoked;
+                    let id = if choked {
+                        MessageType::Choke
+                    } else {
+                        MessageType::Unchoke
+                    };
+                    frame
+                        .send(Message {
+                            id,
+                            payload: Vec::new(),
+                        })
+                        .await?;
+                    continue;
+                }
+            };
+
+            match message.id {
+                MessageType::Interested => {
+                    self.unchoker().set_interested(peer, true, Instant::now());
+                }
+                MessageType::NotIntereted => {
+                    self.unchoker().set_interested(peer, false, Instant::now());
+                }
+                // What the peer has only matters to decide which pieces to offer.
+                MessageType::Bitfield => {
+                    if let Some(mut super_seeder) = self.super_seeder() {
+                        super_seeder.peer_bitfield(peer, &Bitfield::from_bytes(message.payload));
+                    }
+                }
+                MessageType::Have => {
+                    let have = Have::load_from_payload(&message.payload)
+                        .ok_or(anyhow::anyhow!("Invalid have message from peer"))?;
+                    if let Some(mut super_seeder) = self.super_seeder() {
+                        super_seeder.peer_have(peer, have.index as usize);
+                    }
+                }
+                // Requests from a choked peer are discarded.
+                MessageType::Request if !choked => {
+                    let request = Request::load_from_payload(&message.payload)
+                        .ok_or(anyhow::anyhow!("Invalid request from peer"))?;
+                    let index = request.index as usize;
+                    let begin = request.begin as usize;
+                    let block_size = request.length as usize;
+
+                    if !self.serves(peer, index)
+                        || block_size > Self::MAX_REQUEST
+                        || begin + block_size > self.torrent.info.piece_size(index)
+                    {
+                        return Err(anyhow::anyhow!("Invalid request {:?}", request));
+                    }
+
+                    self.limiter.acquire(block_size).await;
+                    self.unchoker().uploaded(peer, block_size);
+
+                    let mut block = vec![0u8; block_size];
+                    let offset = self.torrent.info.piece_offset(index) + begin;
+                    file.seek(SeekFrom::Start(offset as u64)).await?;
+                    file.read_exact(&mut block).await?;
+
+                    let piece = Piece {
+                        index: request.index,
+                        begin: request.begin,
+                        piece: &block,
+                    };
+                    frame
+                        .send(Message {
+                            id: MessageType::Piece,
+                            payload: piece.as_bytes(),
+                        })
+                        .await
+                        .context("send piece message")?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The next piece to offer when super-seeding, never otherwise.
+async fn next_offer(offers: &mut Option<mpsc::UnboundedReceiver<usize>>) -> Option<usize> {
+    match offers {
+        Some(offers) => offers.recv().await,
+        None => std::future::pending().await,
+    }
+}
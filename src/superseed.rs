@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use crate::bitfield::Bitfield;
+
+struct PeerState {
+    // The pieces the peer told us it has, from its bitfield and Have messages.
+    has: Bitfield,
+    // Every piece ever offered to the peer, which it may request.
+    advertised: Bitfield,
+    // The pieces offered to the peer that no other peer has been seen with yet.
+    pending: Vec<usize>,
+    // Pieces to offer, sent as Have messages by the peer's connection.
+    offers: mpsc::UnboundedSender<usize>,
+}
+
+// Super-seeding (BEP 16): instead of its bitfield, an initial seeder offers each peer
+// a single piece with a Have message, and only offers that peer another one once some
+// other peer announces the piece, i.e. once it was passed on. Each piece offered is the
+// one the fewest peers have or were offered, so the first copies spread out over the
+// whole torrent instead of the same pieces being uploaded again and again.
+pub struct SuperSeeder {
+    // The pieces we can serve.
+    ours: Bitfield,
+    // How many connected peers have each piece.
+    availability: Vec<usize>,
+    // How many connected peers each piece is pending with.
+    offered: Vec<usize>,
+    peers: HashMap<SocketAddr, PeerState>,
+    // Where the search for the next piece starts, so that ties go round the torrent.
+    cursor: usize,
+}
+
+impl SuperSeeder {
+    pub fn new(ours: Bitfield, num_pieces: usize) -> Self {
+        Self {
+            ours,
+            availability: vec![0; num_pieces],
+            offered: vec![0; num_pieces],
+            peers: HashMap::new(),
+            cursor: 0,
+        }
+    }
+
+    // A piece verified since seeding started, to offer from now on.
+    pub fn add_piece(&mut self, piece_i: usize) {
+        self.ours.set(piece_i);
+
+        // Peers left without anything to get can use it right away.
+        let idle: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.pending.is_empty())
+            .map(|(&peer, _)| peer)
+            .collect();
+        for peer in idle {
+            self.offer(peer);
+        }
+    }
+
+    // The returned receiver yields the pieces to offer the peer, starting with its first.
+    pub fn register(&mut self, peer: SocketAddr) -> mpsc::UnboundedReceiver<usize> {
+        let num_pieces = self.availability.len();
+        let (offers, rx) = mpsc::unbounded_channel();
+        self.peers.insert(
+            peer,
+            PeerState {
+                has: Bitfield::new(num_pieces),
+                advertised: Bitfield::new(num_pieces),
+                pending: Vec::new(),
+                offers,
+            },
+        );
+        self.offer(peer);
+        rx
+    }
+
+    pub fn remove(&mut self, peer: SocketAddr) {
+        let Some(state) = self.peers.remove(&peer) else {
+            return;
+        };
+        for piece_i in state.has.iter_ones() {
+            self.availability[piece_i] -= 1;
+        }
+        for piece_i in state.pending {
+            self.offered[piece_i] -= 1;
+        }
+    }
+
+    // Whether the peer was offered the piece, and so may request it.
+    pub fn was_offered(&self, peer: SocketAddr, piece_i: usize) -> bool {
+        self.peers
+            .get(&peer)
+            .is_some_and(|state| state.advertised.has(piece_i))
+    }
+
+    // The peer's bitfield. Offers it already had the piece of are replaced.
+    pub fn peer_bitfield(&mut self, peer: SocketAddr, bitfield: &Bitfield) {
+        for piece_i in bitfield.iter_ones() {
+            self.set_has(peer, piece_i);
+        }
+
+        let Some(state) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let had: Vec<_> = state
+            .pending
+            .iter()
+            .copied()
+            .filter(|&piece_i| bitfield.has(piece_i))
+            .collect();
+        for piece_i in had {
+            self.withdraw(peer, piece_i);
+        }
+        if self.peers.get(&peer).is_some_and(|s| s.pending.is_empty()) {
+            self.offer(peer);
+        }
+    }
+
+    // The peer announced a piece with a Have message: the other peers it was offered
+    // to passed it on, so they get their next piece.
+    pub fn peer_have(&mut self, peer: SocketAddr, piece_i: usize) {
+        self.set_has(peer, piece_i);
+
+        let shared: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(&other, state)| other != peer && state.pending.contains(&piece_i))
+            .map(|(&other, _)| other)
+            .collect();
+        for other in shared {
+            self.withdraw(other, piece_i);
+            self.offer(other);
+        }
+    }
+
+    fn set_has(&mut self, peer: SocketAddr, piece_i: usize) {
+        let Some(state) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        if piece_i < self.availability.len() && !state.has.has(piece_i) {
+            state.has.set(piece_i);
+            self.availability[piece_i] += 1;
+        }
+    }
+
+    fn withdraw(&mut self, peer: SocketAddr, piece_i: usize) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.pending.retain(|&p| p != piece_i);
+            self.offered[piece_i] -= 1;
+        }
+    }
+
+    // Offer the peer the piece it lacks that the fewest peers have, then the fewest
+    // were offered. Nothing is offered to a peer that has all our pieces.
+    fn offer(&mut self, peer: SocketAddr) {
+        let Some(state) = self.peers.get(&peer) else {
+            return;
+        };
+        let num_pieces = self.availability.len();
+        let piece = (0..num_pieces)
+            .map(|i| (self.cursor + i) % num_pieces)
+            .filter(|&piece_i| self.ours.has(piece_i) && !state.has.has(piece_i))
+            .filter(|&piece_i| !state.pending.contains(&piece_i))
+            .min_by_key(|&piece_i| (self.availability[piece_i], self.offered[piece_i]));
+        let Some(piece_i) = piece else {
+            return;
+        };
+
+        let state = self.peers.get_mut(&peer).expect("peer is registered");
+        state.pending.push(piece_i);
+        state.advertised.set(piece_i);
+        self.offered[piece_i] += 1;
+        self.cursor = (piece_i + 1) % num_pieces;
+        // The connection is closing when nobody receives anymore.
+        _ = state.offers.send(piece_i);
+    }
+}
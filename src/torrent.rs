@@ -1,7 +1,15 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::bencode;
 
 use hashes::Hashes;
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -9,24 +17,197 @@ use sha1::{Digest, Sha1};
 pub struct Torrent {
     // The URL of the tracker.
     pub announce: String,
+    // announce-list: (optional) tiers of backup trackers, see BEP 12.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // creation date: (optional) the creation time of the torrent, in standard UNIX epoch format.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    // comment: (optional) free-form textual comments of the author.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // created by: (optional) name and version of the program used to create the torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    // url-list: (optional) HTTP mirrors of the content to download pieces from, see BEP 19.
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
     // This maps to a dictionary, with keys described in Info.
     pub info: Info,
+    // The info dictionary exactly as read from the torrent file, which the info hash is taken of.
+    #[serde(skip)]
+    raw_info: Option<Vec<u8>>,
 }
 
 impl Torrent {
     pub const HASH_SIZE: usize = 20;
 
+    // A torrent built in memory rather than read from a file.
+    pub fn new(announce: String, info: Info) -> Self {
+        Self {
+            announce,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            url_list: None,
+            info,
+            raw_info: None,
+        }
+    }
+
+    // A torrent around an info dictionary received as bytes, e.g. from peers over ut_metadata.
+    // The bytes are kept, so the info hash is taken of them rather than of a re-encoding.
+    pub fn from_info_bytes(announce: String, raw_info: Vec<u8>) -> anyhow::Result<Self> {
+        let info: Info = serde_bencode::from_bytes(&raw_info)?;
+        info.validate()?;
+        let mut torrent = Self::new(announce, info);
+        torrent.raw_info = Some(raw_info);
+        Ok(torrent)
+    }
+
+    // Hash the info dictionary as it was read, so key order or encoding details we would
+    // serialize differently cannot change it. Only a torrent built in memory is serialized.
     pub fn info_hash(&self) -> anyhow::Result<[u8; Torrent::HASH_SIZE]> {
-        let info_encoded = serde_bencode::to_bytes(&self.info)?;
         let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        Ok(hasher.finalize().try_into()?)
+        match &self.raw_info {
+            Some(raw_info) => hasher.update(raw_info),
+            None => hasher.update(serde_bencode::to_bytes(&self.info)?),
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    // Size of all the torrent's data, whether it is one file or several.
+    pub fn total_size(&self) -> usize {
+        self.info.total_length()
+    }
+
+    // Trackers grouped by tier. When announce-list is present announce is ignored.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    // Announce to these trackers instead of the torrent's own, all in a single tier.
+    // The info hash is unaffected, it is taken of the info dictionary only.
+    pub fn set_trackers(&mut self, urls: Vec<String>) {
+        if let Some(first) = urls.first() {
+            self.announce = first.clone();
+            self.announce_list = Some(vec![urls]);
+        }
+    }
+
+    // The web seed URLs, leaving out the empty string some torrent creators put there.
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => vec![],
+        };
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
     }
 }
 
+// A single web seed may be given as a string rather than a list.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
 pub fn read_torrent_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Torrent> {
     let content = std::fs::read(path)?;
-    Ok(serde_bencode::from_bytes(&content)?)
+    parse_torrent(&content)
+}
+
+// The info hash of a torrent file's content, from the bytes of its info dictionary
+// alone: nothing else is decoded, so a torrent with fields we cannot parse still works.
+pub fn info_hash_of_bytes(raw: &[u8]) -> anyhow::Result<[u8; Torrent::HASH_SIZE]> {
+    let info = bencode::dict_value(raw, b"info")
+        .filter(|info| info.starts_with(b"d"))
+        .ok_or(anyhow::anyhow!("No info dictionary in torrent"))?;
+    Ok(Sha1::digest(info).into())
+}
+
+// Deserialize a torrent, making sure its pieces cover exactly its length.
+fn parse_torrent(content: &[u8]) -> anyhow::Result<Torrent> {
+    let mut torrent: Torrent = serde_bencode::from_bytes(content)?;
+    torrent.info.validate()?;
+    torrent.raw_info = bencode::dict_value(content, b"info").map(<[u8]>::to_vec);
+    Ok(torrent)
+}
+
+// Read a torrent from `-` (stdin), an http(s) URL, or else a file path.
+pub async fn read_torrent_source(src: &str) -> anyhow::Result<Torrent> {
+    let content = if src == "-" {
+        let mut content = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut content)
+            .context("read torrent from stdin")?;
+        content
+    } else if src.starts_with("http://") || src.starts_with("https://") {
+        let response = reqwest::get(src).await?.error_for_status()?;
+        response
+            .bytes()
+            .await
+            .with_context(|| format!("fetch torrent from {}", src))?
+            .to_vec()
+    } else {
+        std::fs::read(src).with_context(|| format!("read torrent file {}", src))?
+    };
+
+    parse_torrent(&content)
+}
+
+// Build a single-file torrent for `input`, hashing it into pieces of `piece_length` bytes.
+pub fn create_torrent(
+    input: &Path,
+    announce: String,
+    piece_length: usize,
+) -> anyhow::Result<Torrent> {
+    if piece_length == 0 {
+        return Err(anyhow::anyhow!("Piece length must not be zero"));
+    }
+
+    let name = input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(anyhow::anyhow!("Invalid file name: {}", input.display()))?
+        .to_string();
+    let content = std::fs::read(input)?;
+
+    let pieces = content
+        .chunks(piece_length)
+        .map(|piece| Sha1::digest(piece).into())
+        .collect();
+
+    let info = Info {
+        name,
+        plength: piece_length,
+        pieces: Hashes(pieces),
+        keys: Keys::SingleFile {
+            length: content.len(),
+        },
+        private: None,
+        extra: BTreeMap::new(),
+    };
+
+    Ok(Torrent::new(announce, info))
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -35,7 +216,7 @@ pub fn read_torrent_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Torrent> {
 // and encoding it if and only if the bdecoder fully validated the input.
 pub struct Info {
     // name: a UTF-8 encoded string which is the suggested name to save the file (or directory) as
-    pub name: String,
+    name: String,
     // piece length: number of bytes in each piece maps to the number of bytes in each piece the file is split into.
     //
     // For the purposes of transfer, files are split into fixed-size pieces which are all the same length,
@@ -50,9 +231,32 @@ pub struct Info {
     // otherwise it represents a set of files which go in a directory structure
     #[serde(flatten)]
     pub keys: Keys,
+    // private: (optional) when 1, peers may only come from the tracker, never from DHT or PEX (BEP 27).
+    // Left out when absent so the info hash of torrents without it is unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    // Any other keys, such as `source` from private trackers, kept so that serializing
+    // reproduces the original info dictionary and its info hash.
+    #[serde(flatten, deserialize_with = "extra_keys")]
+    pub extra: BTreeMap<String, Value>,
+}
+
+// The keys flattened into `keys` show up here as well, they must not be serialized twice.
+fn extra_keys<'de, D>(deserializer: D) -> Result<BTreeMap<String, Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut extra = BTreeMap::<String, Value>::deserialize(deserializer)?;
+    extra.remove("length");
+    extra.remove("files");
+    Ok(extra)
 }
 
 impl Info {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn file_length(&self) -> Option<usize> {
         if let Keys::SingleFile { length } = self.keys {
             Some(length)
@@ -60,6 +264,97 @@ impl Info {
             None
         }
     }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    // Length of the whole torrent, for multi-file torrents the files concatenated.
+    pub fn total_length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    // Where piece `i` starts in the torrent's data.
+    pub fn piece_offset(&self, i: usize) -> usize {
+        i * self.plength
+    }
+
+    // Length of piece `i`: `plength`, except for a shorter last piece when the total
+    // length is not a multiple of it, and zero past the end.
+    pub fn piece_size(&self, i: usize) -> usize {
+        self.total_length()
+            .saturating_sub(self.piece_offset(i))
+            .min(self.plength)
+    }
+
+    // Where each file lies in the torrent's data, as (offset, length) in `files` order.
+    // A single-file torrent is the one file covering everything.
+    pub fn file_spans(&self) -> Vec<(usize, usize)> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![(0, *length)],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|file| {
+                        let span = (offset, file.length);
+                        offset += file.length;
+                        span
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // The pieces holding any of the data of file `i`, including the pieces it shares
+    // with its neighbours. Empty for an empty file, None if there is no such file.
+    pub fn file_pieces(&self, i: usize) -> Option<Range<usize>> {
+        let (offset, length) = *self.file_spans().get(i)?;
+        if length == 0 {
+            return Some(0..0);
+        }
+
+        Some(offset / self.plength..(offset + length + (self.plength - 1)) / self.plength)
+    }
+
+    // The priority of each piece from `(file index, priority)` pairs, files left out having
+    // priority 0. A piece shared by several files takes the highest of their priorities.
+    pub fn piece_priorities(&self, files: &[(usize, usize)]) -> anyhow::Result<Vec<usize>> {
+        let num_files = self.file_spans().len();
+        let mut priorities = vec![0; self.pieces.num_pieces()];
+        for &(file_i, priority) in files {
+            let pieces = self.file_pieces(file_i).ok_or(anyhow::anyhow!(
+                "No file {}, the torrent has {} files",
+                file_i,
+                num_files
+            ))?;
+            for piece_i in pieces {
+                priorities[piece_i] = priorities[piece_i].max(priority);
+            }
+        }
+        Ok(priorities)
+    }
+
+    // Check there is one piece hash for every `plength` bytes, the last piece possibly shorter.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.plength == 0 {
+            return Err(anyhow::anyhow!("Piece length must not be zero"));
+        }
+
+        let expected = (self.total_length() + (self.plength - 1)) / self.plength;
+        if self.pieces.num_pieces() != expected {
+            return Err(anyhow::anyhow!(
+                "Torrent has {} piece hashes but its length needs {}",
+                self.pieces.num_pieces(),
+                expected
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -77,10 +372,37 @@ pub enum Keys {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     // length - The length of the file, in bytes.
-    length: usize,
+    pub length: usize,
     // path - A list of UTF-8 encoded strings corresponding to subdirectory names,
     // the last of which is the actual file name (a zero length list is an error case).
-    path: Vec<String>,
+    pub path: Vec<String>,
+    // md5sum - (optional) a 32-character hex MD5 sum of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+}
+
+impl File {
+    // Where this file goes under the base directory `base`.
+    // Components that could escape the base directory are rejected.
+    pub fn local_path(&self, base: &Path) -> anyhow::Result<PathBuf> {
+        if self.path.is_empty() {
+            return Err(anyhow::anyhow!("File with an empty path"));
+        }
+
+        let mut local = base.to_path_buf();
+        for component in &self.path {
+            if component.is_empty()
+                || component == "."
+                || component == ".."
+                || component.contains(['/', '\\'])
+            {
+                return Err(anyhow::anyhow!("Invalid file path: {:?}", self.path));
+            }
+            local.push(component);
+        }
+
+        Ok(local)
+    }
 }
 
 mod hashes {
@@ -140,8 +462,18 @@ mod hashes {
         pub fn num_pieces(&self) -> usize {
             self.0.len()
         }
+
+        // The hash of piece `index`, or None past the last piece.
+        pub fn get(&self, index: usize) -> Option<&[u8; 20]> {
+            self.0.get(index)
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &[u8; 20]> {
+            self.0.iter()
+        }
     }
 
+    // Panics past the last piece, use `get` for piece indices coming from peers.
     impl Index<usize> for Hashes {
         type Output = [u8; 20];
 
@@ -150,3 +482,117 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single-file torrent around an info dictionary of `length` bytes in pieces of
+    // `plength`, with `pieces` as its piece hashes.
+    fn torrent_bytes(length: usize, plength: usize, pieces: &[u8]) -> Vec<u8> {
+        let mut content = b"d8:announce9:http://t/4:infod".to_vec();
+        content.extend_from_slice(format!("6:lengthi{}e4:name1:f", length).as_bytes());
+        content.extend_from_slice(format!("12:piece lengthi{}e", plength).as_bytes());
+        content.extend_from_slice(format!("6:pieces{}:", pieces.len()).as_bytes());
+        content.extend_from_slice(pieces);
+        content.extend_from_slice(b"ee");
+        content
+    }
+
+    #[test]
+    fn raw_info_of_sample_torrent() {
+        let torrent = read_torrent_file("sample.torrent").unwrap();
+        let raw_info = torrent.raw_info.as_deref().unwrap();
+        assert!(raw_info.starts_with(b"d") && raw_info.ends_with(b"e"));
+
+        let info_hash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        assert_eq!(hex::encode(Sha1::digest(raw_info)), info_hash);
+        assert_eq!(hex::encode(torrent.info_hash().unwrap()), info_hash);
+    }
+
+    #[test]
+    fn info_hash_with_source_key() {
+        let mut info = b"d6:lengthi50e4:name1:f12:piece lengthi20e6:pieces60:".to_vec();
+        info.extend_from_slice(&[1; 3 * 20]);
+        info.extend_from_slice(b"6:source7:trackere");
+        let mut content = b"d8:announce9:http://t/4:info".to_vec();
+        content.extend_from_slice(&info);
+        content.push(b'e');
+
+        let mut torrent = parse_torrent(&content).unwrap();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        assert_eq!(torrent.info_hash().unwrap(), info_hash);
+
+        // Serialized again, the info dictionary keeps its source and so its hash.
+        torrent.raw_info = None;
+        assert_eq!(torrent.info_hash().unwrap(), info_hash);
+    }
+
+    #[test]
+    fn info_hash_from_info_bytes() {
+        // Keys out of order, which serializing would sort.
+        let mut info = b"d4:name1:f6:lengthi50e12:piece lengthi20e6:pieces60:".to_vec();
+        info.extend_from_slice(&[1; 3 * 20]);
+        info.push(b'e');
+
+        let torrent = Torrent::from_info_bytes("http://t/".into(), info.clone()).unwrap();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        assert_eq!(torrent.info_hash().unwrap(), info_hash);
+        assert_eq!(torrent.info.total_length(), 50);
+
+        assert!(Torrent::from_info_bytes("http://t/".into(), b"d4:name1:fe".to_vec()).is_err());
+    }
+
+    #[test]
+    fn parse_torrent_with_one_hash_per_piece() {
+        let torrent = parse_torrent(&torrent_bytes(50, 20, &[1; 3 * 20])).unwrap();
+        assert_eq!(torrent.info.pieces.num_pieces(), 3);
+    }
+
+    #[test]
+    fn piece_sizes_of_an_exact_multiple_length() {
+        let info = parse_torrent(&torrent_bytes(60, 20, &[1; 3 * 20]))
+            .unwrap()
+            .info;
+
+        assert_eq!(
+            (0..3).map(|i| info.piece_offset(i)).collect::<Vec<_>>(),
+            [0, 20, 40]
+        );
+        assert_eq!(
+            (0..4).map(|i| info.piece_size(i)).collect::<Vec<_>>(),
+            [20, 20, 20, 0]
+        );
+    }
+
+    #[test]
+    fn piece_sizes_with_a_short_last_piece() {
+        let info = parse_torrent(&torrent_bytes(50, 20, &[1; 3 * 20]))
+            .unwrap()
+            .info;
+
+        assert_eq!(
+            (0..3).map(|i| info.piece_offset(i)).collect::<Vec<_>>(),
+            [0, 20, 40]
+        );
+        assert_eq!(
+            (0..4).map(|i| info.piece_size(i)).collect::<Vec<_>>(),
+            [20, 20, 10, 0]
+        );
+    }
+
+    #[test]
+    fn parse_torrent_with_truncated_pieces() {
+        let err = parse_torrent(&torrent_bytes(50, 20, &[1; 3 * 20 - 1])).unwrap_err();
+        assert!(err.to_string().contains("length is 59"), "{}", err);
+    }
+
+    #[test]
+    fn parse_torrent_with_too_many_pieces() {
+        let err = parse_torrent(&torrent_bytes(50, 20, &[1; 4 * 20])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Torrent has 4 piece hashes but its length needs 3"
+        );
+    }
+}
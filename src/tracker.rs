@@ -1,3 +1,10 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
 
 pub use peers::Peers;
@@ -9,7 +16,9 @@ pub struct TrackerRequest {
     // peer_id: a unique identifier for your client
     //
     // A string of length 20 that you get to pick. You can use something like 00112233445566778899.
-    pub peer_id: String,
+    // Sent url encoded byte by byte like the info hash, so it is not serialized here.
+    #[serde(skip)]
+    pub peer_id: [u8; 20],
 
     // port: the port your client is listening on
     //
@@ -36,19 +45,145 @@ pub struct TrackerRequest {
     // For the purposes of this challenge, set this to 1.
     // The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    // no_peer_id: the tracker may leave peer ids out of the peer list.
+    //
+    // Only matters for trackers falling back to the dictionary model, a compact list has no peer ids.
+    pub no_peer_id: u8,
+
+    // key: (optional) a random value identifying our session to the tracker across announces,
+    // even if our IP address changes. Left out when None.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
+    // numwant: (optional) how many peers we would like in the response.
+    //
+    // Trackers default to about 50 when it is left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    // event: if specified, must be one of started, completed, stopped.
+    //
+    // If not specified, then this request is one performed at regular intervals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<TrackerEvent>,
+}
+
+// Generated once per run, so that every announce of the run carries the same key.
+fn session_key() -> &'static str {
+    static KEY: OnceLock<String> = OnceLock::new();
+    KEY.get_or_init(|| format!("{:08X}", rand::random::<u32>()))
+}
+
+// How the HTTP client talking to trackers is set up.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    // Accept any certificate, self-signed, expired or for another host.
+    // DANGER: anyone between us and the tracker can then pose as the tracker.
+    pub insecure: bool,
+    // A PEM root certificate trusted on top of the system ones.
+    pub ca_cert: Option<PathBuf>,
+    // Sent instead of DEFAULT_USER_AGENT, some trackers only serve clients they know.
+    pub user_agent: Option<String>,
+    // Extra headers sent with every request, as (name, value).
+    pub headers: Vec<(String, String)>,
+}
+
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+// A tracker that has not answered by then is treated as down.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Trackers moved to another host or to https redirect the announce, but a longer
+// chain than this is most likely a loop.
+const MAX_REDIRECTS: usize = 5;
+
+// One client for the whole run, so its connections and DNS lookups are reused
+// across announces, re-announces and the trackers of an announce list.
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+// Build the HTTP client shared by every announce and scrape of the run.
+// Has to happen before the first request, which otherwise sets up a default client.
+pub fn configure_client(options: &ClientOptions) -> anyhow::Result<()> {
+    let mut builder = client_builder();
+    if options.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(path) = &options.ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let cert = reqwest::Certificate::from_pem(&pem).context("parse CA certificate")?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(user_agent) = &options.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    if !options.headers.is_empty() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {:?}", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {}", name))?;
+            headers.append(name, value);
+        }
+        builder = builder.default_headers(headers);
+    }
+
+    CLIENT
+        .set(builder.build()?)
+        .map_err(|_| anyhow::anyhow!("Tracker client is configured already"))
+}
+
+// Also used for web seeds, so they get the same certificates and user agent.
+pub(crate) fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(|| {
+        client_builder()
+            .build()
+            .expect("default tracker client builds")
+    })
+}
+
+// The client asks for gzip and inflates gzipped responses on its own.
+fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .user_agent(DEFAULT_USER_AGENT)
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+        .gzip(true)
+}
+
+async fn get(url: String) -> anyhow::Result<Vec<u8>> {
+    let body = client().get(url).send().await?.bytes().await?;
+    Ok(body.to_vec())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerEvent {
+    // The first request to the tracker must include the event key with this value.
+    Started,
+    // Must be sent to the tracker if the client is shutting down gracefully.
+    Stopped,
+    // Must be sent to the tracker when the download completes.
+    Completed,
 }
 
 impl TrackerRequest {
+    // The port announced when none is configured.
     pub const TRACKER_PORT: u16 = 6881;
 
-    pub fn new(peer_id: &str, left: usize) -> Self {
+    pub fn new(peer_id: [u8; 20], port: u16, left: usize) -> Self {
         Self {
-            peer_id: peer_id.to_owned(),
-            port: TrackerRequest::TRACKER_PORT,
+            peer_id,
+            port,
             uploaded: 0,
             downloaded: 0,
-            left: left,
+            left,
             compact: 1,
+            no_peer_id: 1,
+            key: Some(session_key().to_string()),
+            numwant: None,
+            event: None,
         }
     }
 
@@ -57,22 +192,123 @@ impl TrackerRequest {
         url: &str,
         info_hash: [u8; Torrent::HASH_SIZE],
     ) -> anyhow::Result<TrackerResponse> {
-        let request_params = serde_urlencoded::to_string(&self)?;
+        let request_params = serde_urlencoded::to_string(self)?;
 
         let tracker_url = format!(
-            "{}?{}&info_hash={}",
+            "{}?{}&info_hash={}&peer_id={}",
             url,
             request_params,
-            &urlencode(&info_hash)
+            &urlencode(&info_hash),
+            &urlencode(&self.peer_id)
         );
 
-        let response = reqwest::get(tracker_url).await?;
-        let response = response.bytes().await?;
+        let response = get(tracker_url).await?;
+
+        TrackerResponse::parse(&response)
+    }
+
+    // Announce tier by tier: every tracker in a tier is tried and their peers merged,
+    // falling back to the next tier when none of them returned any peers.
+    pub async fn send_tiers(
+        &self,
+        tiers: &[Vec<String>],
+        info_hash: [u8; Torrent::HASH_SIZE],
+    ) -> anyhow::Result<TrackerResponse> {
+        let mut last_resp = None;
+        let mut last_err = None;
+
+        for tier in tiers {
+            let mut merged: Option<TrackerResponse> = None;
+
+            for url in tier {
+                match self.send(url, info_hash).await {
+                    Ok(resp) => match merged.as_mut() {
+                        Some(merged) => {
+                            for peer in resp.peers {
+                                if !merged.peers.0.contains(&peer) {
+                                    merged.peers.0.push(peer);
+                                }
+                            }
+                        }
+                        None => merged = Some(resp),
+                    },
+                    Err(e) => last_err = Some(e.context(format!("announce to {}", url))),
+                }
+            }
+
+            match merged {
+                Some(resp) if !resp.peers.0.is_empty() => return Ok(resp),
+                Some(resp) => last_resp = Some(resp),
+                None => {}
+            }
+        }
+
+        // Some tracker answered, just without peers.
+        if let Some(resp) = last_resp {
+            return Ok(resp);
+        }
 
-        serde_bencode::from_bytes(&response).map_err(|e| anyhow::anyhow!(e))
+        Err(last_err.unwrap_or(anyhow::anyhow!("No tracker to announce to")))
     }
 }
 
+// Swarm statistics of a torrent as reported by the tracker's scrape convention.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScrapeStats {
+    // complete: the number of peers with the entire file, i.e. seeders.
+    pub complete: usize,
+    // downloaded: the total number of times the tracker registered a completion.
+    pub downloaded: usize,
+    // incomplete: the number of non-seeder peers, aka "leechers".
+    pub incomplete: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    // files: a dictionary from the 20 byte info hash to the stats of that torrent.
+    files: HashMap<serde_bytes::ByteBuf, ScrapeStats>,
+}
+
+// Ask the tracker for the swarm statistics of a single torrent.
+pub async fn scrape(
+    announce: &str,
+    info_hash: [u8; Torrent::HASH_SIZE],
+) -> anyhow::Result<ScrapeStats> {
+    let url = format!(
+        "{}?info_hash={}",
+        scrape_url(announce)?,
+        &urlencode(&info_hash)
+    );
+
+    let response = get(url).await?;
+
+    let response: ScrapeResponse =
+        serde_bencode::from_bytes(&response).map_err(|e| anyhow::anyhow!(e))?;
+    response
+        .files
+        .into_iter()
+        .find(|(hash, _)| hash.as_slice() == info_hash)
+        .map(|(_, stats)| stats)
+        .ok_or(anyhow::anyhow!("Tracker has no stats for this torrent"))
+}
+
+// The scrape URL is the announce URL with the `announce` starting its last path segment
+// replaced by `scrape`. Trackers whose announce URL does not follow this do not support scraping.
+fn scrape_url(announce: &str) -> anyhow::Result<String> {
+    let (base, segment) = announce
+        .rsplit_once('/')
+        .ok_or(anyhow::anyhow!("Invalid announce URL: {}", announce))?;
+
+    let Some(rest) = segment.strip_prefix("announce") else {
+        return Err(anyhow::anyhow!(
+            "Tracker does not support scrape: {}",
+            announce
+        ));
+    };
+
+    Ok(format!("{}/scrape{}", base, rest))
+}
+
 // Let's say the hexadecimal representation of our info hash is d69f91e6b2ae4c542468d1073a71d4ea13879a7f
 // This 40 character long string was representing 20 bytes, so each character pair corresponds to a byte
 // We can just put a % before each byte so the URL-encoded representation would be:%d6%9f%91%e6%b2%ae%4c%54%24%68%d1%07%3a%71%d4%ea%13%87%9a%7f
@@ -81,7 +317,7 @@ fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
     encoded
 }
@@ -89,55 +325,121 @@ fn urlencode(t: &[u8; 20]) -> String {
 // The tracker's response will be a bencoded dictionary.
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
+    // failure reason: if present, then no other keys may be present.
+    // A human-readable error message as to why the request failed.
+    #[serde(rename = "failure reason", default)]
+    pub failure_reason: Option<String>,
+
     // interval:
     // An integer, indicating how often your client should make a request to the tracker.
     // You can ignore this value for the purposes of this challenge.
-    pub interval: usize,
+    #[serde(default)]
+    pub interval: Option<usize>,
 
     // peers.
     // A string, which contains list of peers that your client can connect to.
     // Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    #[serde(default)]
     pub peers: Peers,
+
+    // peers6: (optional) like peers, but each peer is an IPv6 address using 18 bytes.
+    // Merged into peers once the response is received.
+    #[serde(default, deserialize_with = "peers::deserialize_peers6")]
+    pub peers6: Peers,
+}
+
+impl TrackerResponse {
+    // Parse an announce response, turning a failure reason into an error.
+    pub fn parse(response: &[u8]) -> anyhow::Result<Self> {
+        let mut response: TrackerResponse =
+            serde_bencode::from_bytes(response).map_err(|e| anyhow::anyhow!(e))?;
+        if let Some(reason) = response.failure_reason.take() {
+            return Err(anyhow::anyhow!("Tracker rejected the announce: {}", reason));
+        }
+        let peers6 = std::mem::take(&mut response.peers6);
+        response.peers.0.extend(peers6);
+
+        Ok(response)
+    }
 }
 
 mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
     use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
     use std::vec::IntoIter;
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
+
+    // Compact peers are 6 bytes for IPv4 (`peers`) or 18 bytes for IPv6 (`peers6`).
+    struct PeersVisitor {
+        ipv6: bool,
+    }
 
-    struct PeersVisitor;
+    // A peer in the non-compact form: a dictionary with `peer id`, `ip` and `port` keys.
+    #[derive(serde::Deserialize)]
+    struct PeerEntry {
+        ip: String,
+        port: u16,
+    }
 
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("an IPv4 socket address, first 4 bytes are peer's IP address, last 2 bytes are the peer's port number")
+            if self.ipv6 {
+                formatter.write_str("an IPv6 socket address, first 16 bytes are peer's IP address, last 2 bytes are the peer's port number")
+            } else {
+                formatter.write_str("an IPv4 socket address, first 4 bytes are peer's IP address, last 2 bytes are the peer's port number, or a list of peer dictionaries")
+            }
         }
 
         fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if value.len() % 6 != 0 {
+            let ip_len = if self.ipv6 { 16 } else { 4 };
+            let chunk_len = ip_len + 2;
+
+            if value.len() % chunk_len != 0 {
                 return Err(E::custom(format!("invalid length: {}", value.len())));
             }
 
             Ok(Peers(
                 value
-                    .chunks_exact(6)
-                    .map(|slice_6| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                            u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                        )
+                    .chunks_exact(chunk_len)
+                    .map(|chunk| {
+                        let ip = if self.ipv6 {
+                            let octets: [u8; 16] =
+                                chunk[..16].try_into().expect("guaranteed to be length 16");
+                            IpAddr::V6(Ipv6Addr::from(octets))
+                        } else {
+                            IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+                        };
+                        let port = u16::from_be_bytes([chunk[ip_len], chunk[ip_len + 1]]);
+                        SocketAddr::new(ip, port)
                     })
                     .collect(),
             ))
         }
+
+        // Trackers may ignore compact=1 and answer with a list of peer dictionaries.
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<PeerEntry>()? {
+                let ip = peer
+                    .ip
+                    .parse::<IpAddr>()
+                    .map_err(|_| de::Error::custom(format!("invalid peer ip: {}", peer.ip)))?;
+                peers.push(SocketAddr::new(ip, peer.port));
+            }
+
+            Ok(Peers(peers))
+        }
     }
 
     impl<'de> Deserialize<'de> for Peers {
@@ -145,14 +447,73 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_bytes(PeersVisitor { ipv6: false })
         }
     }
 
-    impl Peers {
-        // Method to return an iterator over the peers
-        pub fn into_iter(self) -> IntoIter<SocketAddrV4> {
+    // Deserialize the compact IPv6 peers found under the `peers6` key.
+    pub fn deserialize_peers6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PeersVisitor { ipv6: true })
+    }
+
+    // Iterate over the peers by value.
+    impl IntoIterator for Peers {
+        type Item = SocketAddr;
+        type IntoIter = IntoIter<SocketAddr>;
+
+        fn into_iter(self) -> Self::IntoIter {
             self.0.into_iter()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(response: &[u8]) -> Vec<String> {
+        let response: TrackerResponse = serde_bencode::from_bytes(response).unwrap();
+        response
+            .peers
+            .0
+            .iter()
+            .chain(&response.peers6.0)
+            .map(|peer| peer.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn peers_as_dictionaries() {
+        let response = b"d5:peersld2:ip9:127.0.0.17:peer id20:-TS0001-0000000000004:porti6881eed2:ip3:::14:porti51413eeee";
+        assert_eq!(peers(response), ["127.0.0.1:6881", "[::1]:51413"]);
+    }
+
+    #[test]
+    fn compact_ipv4_peers() {
+        let response = b"d5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\xc8\xd5e";
+        assert_eq!(peers(response), ["127.0.0.1:6881", "10.0.0.2:51413"]);
+    }
+
+    #[test]
+    fn compact_ipv6_peers() {
+        let mut response = b"d5:peers0:6:peers618:".to_vec();
+        response.extend([0; 15]);
+        response.extend([1, 0x1a, 0xe1, b'e']);
+        assert_eq!(peers(&response), ["[::1]:6881"]);
+    }
+
+    #[test]
+    fn failure_reason() {
+        let err = TrackerResponse::parse(b"d14:failure reason9:try latere").unwrap_err();
+        assert_eq!(err.to_string(), "Tracker rejected the announce: try later");
+    }
+
+    #[test]
+    fn compact_peers_of_a_wrong_length() {
+        let response = b"d5:peers5:\x7f\x00\x00\x01\x1ae";
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(response).is_err());
+    }
+}
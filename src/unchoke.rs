@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+// Peers unchoked for the most data sent to them in the last round.
+const UNCHOKE_SLOTS: usize = 3;
+// The optimistic slot moves on to another interested peer this often.
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+struct PeerState {
+    interested: bool,
+    // Bytes sent to the peer since the last round started.
+    uploaded: usize,
+    // Whether the peer is unchoked, watched by its connection to send Choke/Unchoke.
+    unchoked: watch::Sender<bool>,
+}
+
+// Decides which of the connected peers are unchoked: the few interested ones we
+// uploaded the most to (tit-for-tat), plus one optimistic slot rotated on a timer
+// so new peers get a chance to prove themselves.
+//
+// Time is always passed in, so the rotation does not depend on the real clock.
+#[derive(Default)]
+pub struct UnchokeManager {
+    peers: HashMap<SocketAddr, PeerState>,
+    optimistic: Option<SocketAddr>,
+    // When the optimistic slot last moved.
+    rotated: Option<Instant>,
+}
+
+impl UnchokeManager {
+    // The returned receiver flips to true when the peer is unchoked, and back on choke.
+    pub fn register(&mut self, peer: SocketAddr) -> watch::Receiver<bool> {
+        let (unchoked, rx) = watch::channel(false);
+        self.peers.insert(
+            peer,
+            PeerState {
+                interested: false,
+                uploaded: 0,
+                unchoked,
+            },
+        );
+        rx
+    }
+
+    pub fn remove(&mut self, peer: SocketAddr, now: Instant) {
+        if self.peers.remove(&peer).is_some() {
+            self.rechoke(now);
+        }
+    }
+
+    pub fn set_interested(&mut self, peer: SocketAddr, interested: bool, now: Instant) {
+        match self.peers.get_mut(&peer) {
+            Some(state) if state.interested != interested => state.interested = interested,
+            _ => return,
+        }
+        self.rechoke(now);
+    }
+
+    pub fn uploaded(&mut self, peer: SocketAddr, bytes: usize) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.uploaded += bytes;
+        }
+    }
+
+    pub fn is_unchoked(&self, peer: SocketAddr) -> bool {
+        self.peers
+            .get(&peer)
+            .is_some_and(|state| *state.unchoked.borrow())
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    // End the current round: pick the unchoked peers again and start counting anew.
+    pub fn next_round(&mut self, now: Instant) {
+        self.rechoke(now);
+        for state in self.peers.values_mut() {
+            state.uploaded = 0;
+        }
+    }
+
+    // Work out the unchoked set and notify every peer whose state changed.
+    pub fn rechoke(&mut self, now: Instant) {
+        let mut interested: Vec<(SocketAddr, usize)> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.interested)
+            .map(|(&peer, state)| (peer, state.uploaded))
+            .collect();
+        // Most uploaded first, ties broken by address so the order is stable.
+        interested.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let regular: Vec<SocketAddr> = interested
+            .iter()
+            .take(UNCHOKE_SLOTS)
+            .map(|&(peer, _)| peer)
+            .collect();
+        let mut candidates: Vec<SocketAddr> = interested
+            .iter()
+            .skip(UNCHOKE_SLOTS)
+            .map(|&(peer, _)| peer)
+            .collect();
+        candidates.sort();
+
+        let expired = self.rotated.map_or(true, |rotated| {
+            now.duration_since(rotated) >= OPTIMISTIC_INTERVAL
+        });
+        let still_candidate = self
+            .optimistic
+            .is_some_and(|peer| candidates.contains(&peer));
+        if expired || !still_candidate {
+            // Round-robin over the candidates, starting after the current optimistic peer.
+            let next = self
+                .optimistic
+                .and_then(|current| candidates.iter().find(|&&peer| peer > current))
+                .or(candidates.first())
+                .copied();
+            if next != self.optimistic || expired {
+                self.rotated = Some(now);
+            }
+            self.optimistic = next;
+        }
+
+        for (peer, state) in &self.peers {
+            let unchoke = regular.contains(peer) || self.optimistic == Some(*peer);
+            state.unchoked.send_if_modified(|unchoked| {
+                let changed = *unchoked != unchoke;
+                *unchoked = unchoke;
+                changed
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn unchoke_top_uploaders_and_rotate_optimistic() {
+        let start = Instant::now();
+        let mut manager = UnchokeManager::default();
+        let peers: Vec<_> = (1..=5).map(peer).collect();
+        let mut watches: Vec<_> = peers.iter().map(|&p| manager.register(p)).collect();
+        for &p in &peers {
+            manager.set_interested(p, true, start);
+        }
+
+        // Peers 1 to 3 took the most, 4 and 5 share the optimistic slot.
+        let round = |manager: &mut UnchokeManager, now: Instant| {
+            for (&p, bytes) in peers.iter().zip([300, 200, 100, 0, 0]) {
+                manager.uploaded(p, bytes);
+            }
+            manager.next_round(now);
+        };
+        let unchoked = |manager: &UnchokeManager| -> Vec<bool> {
+            peers.iter().map(|&p| manager.is_unchoked(p)).collect()
+        };
+
+        round(&mut manager, start);
+        assert_eq!(unchoked(&manager), [true, true, true, true, false]);
+        assert_eq!(manager.optimistic(), Some(peer(4)));
+        assert!(*watches[3].borrow_and_update());
+        assert!(!*watches[4].borrow_and_update());
+
+        // The slot stays put until its interval is over.
+        round(&mut manager, start + Duration::from_secs(10));
+        assert_eq!(manager.optimistic(), Some(peer(4)));
+
+        round(&mut manager, start + OPTIMISTIC_INTERVAL);
+        assert_eq!(manager.optimistic(), Some(peer(5)));
+        assert_eq!(unchoked(&manager), [true, true, true, false, true]);
+        assert!(watches[3].has_changed().unwrap());
+        assert!(!*watches[3].borrow_and_update());
+
+        // And wraps around the candidates.
+        round(&mut manager, start + 2 * OPTIMISTIC_INTERVAL);
+        assert_eq!(manager.optimistic(), Some(peer(4)));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let now = Instant::now();
+        let mut manager = UnchokeManager::default();
+        manager.register(peer(1));
+        manager.register(peer(2));
+        manager.set_interested(peer(1), true, now);
+
+        manager.next_round(now);
+        assert!(manager.is_unchoked(peer(1)));
+        assert!(!manager.is_unchoked(peer(2)));
+
+        // Losing interest gives the slot up right away.
+        manager.set_interested(peer(1), false, now);
+        assert!(!manager.is_unchoked(peer(1)));
+        assert_eq!(manager.optimistic(), None);
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::torrent::{Keys, Torrent};
+use crate::worker::hash_piece;
+
+use anyhow::Context;
+use md5::{Digest, Md5};
+
+// Hash the data found on disk piece by piece and compare it against the torrent.
+// Returns whether each piece matched, in piece order.
+//
+// For a single-file torrent `path` is the file itself, for a multi-file torrent it is
+// the directory holding the files, which are read as one stream in `files` order.
+pub fn verify(torrent: &Torrent, path: &Path) -> anyhow::Result<Vec<bool>> {
+    let paths = match &torrent.info.keys {
+        Keys::SingleFile { .. } => vec![path.to_path_buf()],
+        Keys::MultiFile { files } => files
+            .iter()
+            .map(|file| file.local_path(path))
+            .collect::<anyhow::Result<Vec<PathBuf>>>()?,
+    };
+
+    let mut data: Box<dyn Read> = Box::new(std::io::empty());
+    for path in paths {
+        let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+        data = Box::new(data.chain(file));
+    }
+
+    let num_pieces = torrent.info.pieces.num_pieces();
+    let mut piece = vec![0u8; torrent.info.plength];
+    let mut passed = Vec::with_capacity(num_pieces);
+
+    for piece_i in 0..num_pieces {
+        let piece_size = torrent.info.piece_size(piece_i);
+        // A file shorter than the torrent fails every piece it does not fully cover.
+        if data.read_exact(&mut piece[..piece_size]).is_err() {
+            passed.resize(num_pieces, false);
+            break;
+        }
+
+        passed.push(hash_piece(&piece[..piece_size]) == torrent.info.pieces[piece_i]);
+    }
+
+    Ok(passed)
+}
+
+// Check the files of a multi-file torrent that carry an md5sum against their MD5.
+// Returns each checked file with whether it matched, files without one are left out.
+pub fn verify_md5(torrent: &Torrent, path: &Path) -> anyhow::Result<Vec<(PathBuf, bool)>> {
+    let Keys::MultiFile { files } = &torrent.info.keys else {
+        return Ok(Vec::new());
+    };
+
+    let mut checked = Vec::new();
+    for file in files {
+        let Some(md5sum) = &file.md5sum else {
+            continue;
+        };
+        let path = file.local_path(path)?;
+
+        let mut data = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+        let mut hasher = Md5::new();
+        std::io::copy(&mut data, &mut hasher)
+            .with_context(|| format!("read {}", path.display()))?;
+
+        let matched = hex::encode(hasher.finalize()).eq_ignore_ascii_case(md5sum);
+        checked.push((path, matched));
+    }
+
+    Ok(checked)
+}
@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::header::{ACCEPT_ENCODING, RANGE};
+use reqwest::{StatusCode, Url};
+use sha1::{Digest, Sha1};
+use tokio::sync::mpsc::Sender;
+use tracing::debug;
+
+use crate::bitfield::Bitfield;
+use crate::picker::PiecePicker;
+use crate::rate::RateLimiter;
+use crate::torrent::{Keys, Torrent};
+use crate::tracker;
+use crate::worker::HashMismatch;
+
+// Downloads pieces from an HTTP mirror of the torrent's content (BEP 19), with range
+// requests into the files. A web seed has every piece, so it takes whatever the picker
+// hands out and delivers to the same channel as the peer workers.
+pub struct WebSeedWorker {
+    torrent: Arc<Torrent>,
+    // The URL of each file of the torrent, in `files` order.
+    urls: Vec<Url>,
+    // Every piece, to take any of them from the picker.
+    bitfield: Bitfield,
+    // Shared with the peer workers to cap the overall download rate.
+    limiter: RateLimiter,
+}
+
+impl WebSeedWorker {
+    // Delay before trying again after a failed piece, doubled every time.
+    const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+    // For a single-file torrent `url` is the file itself, unless it ends with a slash
+    // and the file is named after the torrent under it. A multi-file torrent lives in a
+    // directory named after the torrent under `url`.
+    pub fn new(torrent: Arc<Torrent>, url: &str) -> anyhow::Result<Self> {
+        let base = Url::parse(url).with_context(|| format!("invalid web seed URL {}", url))?;
+        if !matches!(base.scheme(), "http" | "https") {
+            return Err(anyhow::anyhow!("Unsupported web seed URL {}", url));
+        }
+
+        let info = &torrent.info;
+        let urls = match &info.keys {
+            Keys::SingleFile { .. } if !url.ends_with('/') => vec![base],
+            Keys::SingleFile { .. } => vec![file_url(&base, &[info.name().to_string()])?],
+            Keys::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    let mut path = vec![info.name().to_string()];
+                    path.extend(file.path.iter().cloned());
+                    file_url(&base, &path)
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        let num_pieces = info.pieces.num_pieces();
+        let mut bitfield = Bitfield::new(num_pieces);
+        for piece_i in 0..num_pieces {
+            bitfield.set(piece_i);
+        }
+
+        Ok(Self {
+            torrent,
+            urls,
+            bitfield,
+            limiter: RateLimiter::default(),
+        })
+    }
+
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    // Download pieces until nobody waits for them anymore. A failed piece goes back to
+    // the picker for the peers, and after `max_attempts` failures in a row the web seed
+    // is given up on.
+    pub async fn download_queue(
+        &self,
+        picker: PiecePicker,
+        result: Sender<(usize, Vec<u8>)>,
+        max_attempts: usize,
+    ) -> anyhow::Result<()> {
+        let mut backoff = Self::RETRY_BACKOFF;
+        let mut attempt = 1;
+
+        while !result.is_closed() {
+            // Pieces given back by failed peers show up again, so keep checking.
+            let Some(piece_i) = picker.take_piece_in(&self.bitfield) else {
+                tokio::time::sleep(Self::RETRY_BACKOFF).await;
+                continue;
+            };
+
+            match self.download_piece(piece_i).await {
+                Ok(piece_data) => {
+                    backoff = Self::RETRY_BACKOFF;
+                    attempt = 1;
+                    if result.send((piece_i, piece_data)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if e.is::<HashMismatch>() {
+                        picker.fail_piece(piece_i);
+                    } else {
+                        picker.push_piece(piece_i);
+                    }
+                    if attempt >= max_attempts {
+                        return Err(e);
+                    }
+                    debug!("Web seed failed: {}, retrying in {:?}", e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "webseed", skip(self))]
+    pub async fn download_piece(&self, piece_i: usize) -> anyhow::Result<Vec<u8>> {
+        let info = &self.torrent.info;
+        let start = info.piece_offset(piece_i);
+        let end = start + info.piece_size(piece_i);
+
+        // A piece may span several files, each fetched with its own range request.
+        let mut piece_data = Vec::with_capacity(end - start);
+        for (url, (offset, length)) in self.urls.iter().zip(info.file_spans()) {
+            let from = start.max(offset);
+            let to = end.min(offset + length);
+            if from >= to {
+                continue;
+            }
+
+            self.limiter.acquire(to - from).await;
+            let data = self
+                .fetch_range(url, from - offset, to - offset, length)
+                .await
+                .with_context(|| format!("fetch piece {} from {}", piece_i, url))?;
+            piece_data.extend_from_slice(&data);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&piece_data).into();
+        if hash != info.pieces[piece_i] {
+            return Err(HashMismatch(piece_i).into());
+        }
+        debug!("Downloaded piece {}", piece_i);
+
+        Ok(piece_data)
+    }
+
+    // Bytes `from..to` of the file at `url`, which is `length` bytes long.
+    async fn fetch_range(
+        &self,
+        url: &Url,
+        from: usize,
+        to: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let response = tracker::client()
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", from, to - 1))
+            // A compressed body could not be cut at byte offsets.
+            .header(ACCEPT_ENCODING, "identity")
+            .send()
+            .await?
+            .error_for_status()?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        let data = match status {
+            StatusCode::PARTIAL_CONTENT => Some(&body[..]),
+            // The server ignored the range and sent the whole file.
+            StatusCode::OK if body.len() == length => body.get(from..to),
+            _ => None,
+        };
+        match data {
+            Some(data) if data.len() == to - from => Ok(data.to_vec()),
+            _ => Err(anyhow::anyhow!(
+                "Unexpected {} response of {} bytes for {} bytes",
+                status,
+                body.len(),
+                to - from
+            )),
+        }
+    }
+}
+
+fn file_url(base: &Url, path: &[String]) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid web seed URL {}", base))?
+        .pop_if_empty()
+        .extend(path);
+    Ok(url)
+}
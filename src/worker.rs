@@ -1,57 +1,187 @@
-use std::ops::Range;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{collections::vec_deque::VecDeque, sync::Mutex};
+use std::time::Duration;
 
+use crate::bitfield::Bitfield;
+use crate::extension::{ExtendedMessage, ExtensionHandshake, PexMessage, UT_PEX_ID};
 use crate::handshake;
 use crate::peer;
+use crate::picker::PiecePicker;
+use crate::pool::{
+    ActivePeers, BadPeers, BannedPeer, Connection, DuplicatePeer, PeerConnectionPool,
+};
+use crate::rate::RateLimiter;
+use crate::report::{QuietReporter, Reporter};
 use crate::torrent::Torrent;
+use crate::writer::write_error;
 
 use anyhow::Context;
 use futures_util::{SinkExt, StreamExt};
 use sha1::{Digest, Sha1};
-use tokio::{net::TcpStream, sync::mpsc::Sender};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::time::Instant;
 use tokio_util::codec::Framed;
+use tracing::debug;
 
-use handshake::Handshake;
-use peer::{Message, MessageFrame, MessageType, Piece, Request};
+use handshake::{Handshake, PeerStream, Timeouts};
+use peer::{Cancel, Have, KeepAlive, Message, MessageFrame, MessageType, Piece, Request};
 
 pub struct Worker {
     torrent: Arc<Torrent>,
-    peer: String,
+    peer: SocketAddr,
+    peer_id: [u8; 20],
+    timeouts: Timeouts,
+    // Shared with the other workers to cap the overall download rate.
+    limiter: RateLimiter,
+    // Where peers learned through peer exchange go, if enabled.
+    pex: Option<UnboundedSender<SocketAddr>>,
+    // Where the connection is kept between downloads from this peer.
+    pool: PeerConnectionPool,
+    // The peer ids connected to by all workers, to skip a client we already talk to.
+    active: ActivePeers,
+    // Told about new connections.
+    reporter: Arc<dyn Reporter>,
+    // The peers that sent corrupt pieces, shared with the other workers.
+    bad_peers: BadPeers,
 }
 
 impl Worker {
-    const PEER_ID_BYTES: [u8; 20] = *b"00112233445566778899";
-
     // Each block max size is 16 kiB (16 * 1024 bytes)
     const BLOCK_SIZE: usize = 1 << 14;
 
-    pub fn new(torrent: Arc<Torrent>, peer: String) -> Self {
-        Self { torrent, peer }
+    // Peers drop connections idle for about two minutes, so stay well below that.
+    // Shorter message timeouts shorten it, see keepalive_interval.
+    const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+    // Block requests kept outstanding on a connection while draining the queue.
+    const PIPELINE_DEPTH: usize = 10;
+
+    // How many times a block is requested before giving up on the piece.
+    const BLOCK_ATTEMPTS: u32 = 3;
+
+    // Delay before the first reconnect, doubled after every failed attempt.
+    const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+    pub fn new(torrent: Arc<Torrent>, peer: SocketAddr, peer_id: [u8; 20]) -> Self {
+        Self {
+            torrent,
+            peer,
+            peer_id,
+            timeouts: Timeouts::default(),
+            limiter: RateLimiter::default(),
+            pex: None,
+            pool: PeerConnectionPool::default(),
+            active: ActivePeers::default(),
+            reporter: Arc::new(QuietReporter),
+            bad_peers: BadPeers::default(),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    // Ask peers for the peers they know (ut_pex) and send those to `peers`.
+    pub fn with_pex(mut self, peers: UnboundedSender<SocketAddr>) -> Self {
+        self.pex = Some(peers);
+        self
+    }
+
+    pub fn with_pool(mut self, pool: PeerConnectionPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn with_active_peers(mut self, active: ActivePeers) -> Self {
+        self.active = active;
+        self
+    }
+
+    pub fn with_reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
     }
 
-    pub async fn connect(&self) -> anyhow::Result<TcpStream> {
+    pub fn with_bad_peers(mut self, bad_peers: BadPeers) -> Self {
+        self.bad_peers = bad_peers;
+        self
+    }
+
+    pub async fn connect(&self) -> anyhow::Result<(PeerStream, Handshake)> {
         let info_hash = self.torrent.info_hash()?;
 
-        let mut handshake = Handshake::new(info_hash, Self::PEER_ID_BYTES);
-        let stream = handshake.send(&self.peer).await?;
+        let mut handshake = Handshake::new(info_hash, self.peer_id);
+        handshake.timeouts = self.timeouts;
+        if self.pex.is_some() {
+            handshake.enable_extensions();
+        }
+        let stream = handshake.send(self.peer).await?;
+        debug!(
+            "Handshake done with peer id {}",
+            hex::encode(handshake.peer_id)
+        );
 
-        Ok(stream)
+        Ok((stream, handshake))
+    }
+
+    // Reuse the pooled connection to the peer if there is one, otherwise connect and
+    // wait to be unchoked.
+    async fn open(&self) -> anyhow::Result<Connection> {
+        if let Some(mut connection) = self.pool.take(self.peer) {
+            debug!("Reusing pooled connection");
+            // A worker finding nothing to download sends nothing over the connection
+            // before putting it back, so it is kept alive from here.
+            if connection.keepalive_sent.elapsed() >= self.keepalive_interval() {
+                connection
+                    .frame
+                    .send(KeepAlive)
+                    .await
+                    .context("send keepalive")?;
+                connection.keepalive_sent = Instant::now();
+            }
+            return Ok(connection);
+        }
+
+        let (stream, handshake) = self.connect().await?;
+        // The same client may be known under another address, from the tracker or PEX.
+        let active = self.active.register(handshake.peer_id)?;
+        let (mut frame, bitfield) = self.init_frame(stream).await?;
+        self.reporter.peer_connected(self.peer);
+
+        if self.pex.is_some() && handshake.supports_extensions() {
+            frame
+                .send(Message {
+                    id: MessageType::Extended,
+                    payload: ExtensionHandshake::new().as_bytes()?,
+                })
+                .await
+                .context("send extension handshake")?;
+        }
+
+        Ok(Connection {
+            frame,
+            bitfield,
+            active,
+            keepalive_sent: Instant::now(),
+        })
     }
 
     pub async fn init_frame(
         &self,
-        stream: TcpStream,
-    ) -> anyhow::Result<Framed<TcpStream, MessageFrame>> {
+        stream: PeerStream,
+    ) -> anyhow::Result<(Framed<PeerStream, MessageFrame>, Bitfield)> {
         let mut frame = tokio_util::codec::Framed::new(stream, MessageFrame);
-        let bitfield_msg = frame
-            .next()
-            .await
-            .expect("wait bitfield message")
-            .context("invalid message")?;
-
-        assert_eq!(MessageType::Bitfield, bitfield_msg.id);
+        let num_pieces = self.torrent.info.pieces.num_pieces();
 
+        // Tell the peer we are interested right away, the peer may well wait for it
+        // before sending anything more than its first Have messages.
         frame
             .send(Message {
                 id: MessageType::Interested,
@@ -60,39 +190,232 @@ impl Worker {
             .await
             .context("send interested message")?;
 
-        let unchoke = frame
-            .next()
-            .await
-            .expect("wait unchoke message")
-            .context("invalid message while waiting unchoke")?;
+        // Until we are unchoked, collect which pieces the peer has from whatever it sends:
+        // a Bitfield, Have messages in any order around it, or the Fast Extension's Have All / Have None.
+        let mut bitfield = Bitfield::new(num_pieces);
+        loop {
+            let message = self
+                .next_message(&mut frame)
+                .await?
+                .ok_or_else(|| {
+                    // Overloaded peers often hang up right after the handshake.
+                    if bitfield.count_ones() == 0 {
+                        anyhow::anyhow!("Peer closed before sending its bitfield")
+                    } else {
+                        anyhow::anyhow!("Peer closed while waiting unchoke")
+                    }
+                })?
+                .context("invalid message while waiting unchoke")?;
+
+            match message.id {
+                MessageType::Unchoke => {
+                    if !message.payload.is_empty() {
+                        return Err(anyhow::anyhow!("Unexpected payload in unchoke message"));
+                    }
+                    break;
+                }
+                MessageType::Bitfield => {
+                    let haves =
+                        std::mem::replace(&mut bitfield, Bitfield::from_bytes(message.payload));
+                    for piece_i in haves.iter_ones() {
+                        bitfield.set(piece_i);
+                    }
+                }
+                MessageType::Have => bitfield.set(have_index(message.payload, num_pieces)?),
+                MessageType::HaveAll => {
+                    for piece_i in 0..num_pieces {
+                        bitfield.set(piece_i);
+                    }
+                }
+                MessageType::HaveNone => bitfield = Bitfield::new(num_pieces),
+                // Still choked, keep waiting.
+                MessageType::Choke => {}
+                id => return Err(anyhow::anyhow!("Expected unchoke message but got {:?}", id)),
+            }
+        }
+        debug!("Peer has {} pieces", bitfield.count_ones());
+
+        Ok((frame, bitfield))
+    }
+
+    // The pieces the peer advertises right after the handshake, without waiting to be unchoked.
+    pub async fn peer_pieces(&self) -> anyhow::Result<Bitfield> {
+        let (stream, _) = self.connect().await?;
+        let mut frame = tokio_util::codec::Framed::new(stream, MessageFrame);
+        let num_pieces = self.torrent.info.pieces.num_pieces();
+
+        let mut bitfield = Bitfield::new(num_pieces);
+        loop {
+            let message = self
+                .next_message(&mut frame)
+                .await?
+                .ok_or(anyhow::anyhow!("Peer closed before sending its pieces"))?
+                .context("invalid message while waiting pieces")?;
+
+            match message.id {
+                // Have messages may come before the bitfield, keep them.
+                MessageType::Have => bitfield.set(have_index(message.payload, num_pieces)?),
+                MessageType::Bitfield => {
+                    for piece_i in Bitfield::from_bytes(message.payload).iter_ones() {
+                        if piece_i < num_pieces {
+                            bitfield.set(piece_i);
+                        }
+                    }
+                    break;
+                }
+                MessageType::HaveAll => {
+                    for piece_i in 0..num_pieces {
+                        bitfield.set(piece_i);
+                    }
+                    break;
+                }
+                MessageType::HaveNone => break,
+                // A peer with no pieces may skip the bitfield altogether.
+                _ => break,
+            }
+        }
+
+        Ok(bitfield)
+    }
+
+    // Wait for the next message from the peer, giving up after the message timeout.
+    //
+    // While waiting, a keepalive goes out every keepalive interval so the peer does not drop
+    // us for inactivity. It is sent from this task, so it never lands in the middle of another message.
+    async fn next_message(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+    ) -> anyhow::Result<Option<std::io::Result<Message>>> {
+        let deadline = tokio::time::sleep(self.timeouts.message);
+        tokio::pin!(deadline);
+        let period = self.keepalive_interval();
+        let mut keepalive = tokio::time::interval_at(Instant::now() + period, period);
+
+        loop {
+            tokio::select! {
+                message = frame.next() => match message {
+                    Some(Ok(message)) if message.id == MessageType::Extended => {
+                        if let Some(extended) = ExtendedMessage::from_message(&message) {
+                            self.handle_extended(extended);
+                        }
+                    }
+                    // Messages of extensions we do not support are skipped.
+                    Some(Ok(Message { id: MessageType::Unknown(id), .. })) => {
+                        debug!("Skipping message with unknown id {}", id);
+                    }
+                    message => return Ok(message),
+                },
+                _ = keepalive.tick() => {
+                    frame.send(KeepAlive).await.context("send keepalive")?;
+                }
+                _ = &mut deadline => return Err(anyhow::anyhow!("peer message timed out")),
+            }
+        }
+    }
 
-        assert_eq!(MessageType::Unchoke, unchoke.id);
-        assert!(unchoke.payload.is_empty());
+    // Half the message timeout when that is shorter than KEEPALIVE_INTERVAL, so a
+    // keepalive still goes out before we give up waiting on the peer.
+    fn keepalive_interval(&self) -> Duration {
+        Self::KEEPALIVE_INTERVAL.min(self.timeouts.message / 2)
+    }
 
-        Ok(frame)
+    // Extended messages can arrive at any time, so they are handled as they come in.
+    // Only ut_pex is understood, everything else is ignored.
+    fn handle_extended(&self, extended: ExtendedMessage) {
+        let (Some(pex), UT_PEX_ID) = (&self.pex, extended.id) else {
+            return;
+        };
+
+        if let Some(message) = PexMessage::from_payload(&extended.payload) {
+            for peer in message.added {
+                // Nobody is listening once the download is over.
+                _ = pex.send(peer);
+            }
+        }
+    }
+
+    // Ask the peer to drop a block request we gave up on.
+    // Best effort only, the connection may already be gone.
+    async fn cancel(&self, frame: &mut Framed<PeerStream, MessageFrame>, request: Request) {
+        _ = frame
+            .send(Message {
+                id: MessageType::Cancel,
+                payload: Cancel::from(request).as_bytes().to_vec(),
+            })
+            .await;
+    }
+
+    // Wait for the response to a block request, recording any pieces the peer announces meanwhile.
+    async fn next_block_message(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+        bitfield: &mut Bitfield,
+        picker: Option<&PiecePicker>,
+    ) -> anyhow::Result<Message> {
+        loop {
+            let message = self
+                .next_message(frame)
+                .await?
+                .ok_or(anyhow::anyhow!(
+                    "Peer closed while waiting request response"
+                ))?
+                .context("invalid request response")?;
+
+            if message.id != MessageType::Have {
+                return Ok(message);
+            }
+
+            let index = have_index(message.payload, self.torrent.info.pieces.num_pieces())?;
+            if !bitfield.has(index) {
+                bitfield.set(index);
+                if let Some(picker) = picker {
+                    picker.add_piece(index);
+                }
+            }
+        }
     }
 
     pub async fn download_piece(&self, piece_id: usize) -> anyhow::Result<Vec<u8>> {
-        let stream = self.connect().await?;
-        let mut frame = self.init_frame(stream).await?;
+        let mut piece = Vec::new();
+        self.download_piece_to(piece_id, &mut piece).await?;
+        Ok(piece)
+    }
 
-        // Start download piece speficied by piece id.
-        let num_pieces = self.torrent.info.pieces.0.len();
-        assert!(piece_id < num_pieces);
+    // Download a piece and write it to `writer` once its hash matched, so nothing but a
+    // verified piece ever reaches the writer. On a write error the caller must discard
+    // whatever part of the piece was written.
+    #[tracing::instrument(name = "peer", skip(self, writer), fields(addr = %self.peer))]
+    pub async fn download_piece_to<W>(&self, piece_id: usize, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // Any error below drops the connection instead of returning it to the pool.
+        let Connection {
+            mut frame,
+            mut bitfield,
+            active,
+            keepalive_sent,
+        } = self.open().await?;
 
-        let length = self
-            .torrent
-            .info
-            .file_length()
-            .ok_or(anyhow::anyhow!("MultiFile is unsupported"))?;
+        // Start download piece speficied by piece id.
+        let num_pieces = self.torrent.info.pieces.num_pieces();
+        if piece_id >= num_pieces {
+            return Err(anyhow::anyhow!(
+                "Piece {} out of range, torrent has {} pieces",
+                piece_id,
+                num_pieces
+            ));
+        }
+        if !bitfield.has(piece_id) {
+            return Err(anyhow::anyhow!("Peer does not have piece {}", piece_id));
+        }
 
-        // Last piece may not equal to defined plength.
-        let piece_size = get_residual_size(piece_id, num_pieces, length, self.torrent.info.plength);
+        let piece_size = self.torrent.info.piece_size(piece_id);
 
         // Break the piece into blocks of 16 kiB (16 * 1024 bytes) and send a request message for each block
         let num_blocks = (piece_size + (Self::BLOCK_SIZE - 1)) / Self::BLOCK_SIZE;
 
-        let mut block_data = Vec::with_capacity(piece_size);
+        let mut piece = Vec::with_capacity(piece_size);
 
         for block in 0..num_blocks {
             // The last block will contain 2^14 bytes or less, need to calculate this value using the max block size.
@@ -104,6 +427,66 @@ impl Worker {
                 length: block_size as u32,
             };
 
+            let block = self
+                .request_block(&mut frame, &mut bitfield, request)
+                .await?;
+            piece.extend_from_slice(&block);
+        }
+
+        if piece.len() != piece_size {
+            return Err(anyhow::anyhow!(
+                "Piece {} has length {} but expected {}",
+                piece_id,
+                piece.len(),
+                piece_size
+            ));
+        }
+
+        // Check hash before committing the data.
+        let hash: [u8; 20] = Sha1::digest(&piece).into();
+        let piece_hash = self
+            .torrent
+            .info
+            .pieces
+            .get(piece_id)
+            .ok_or(anyhow::anyhow!("Unexpected piece_id: {}", piece_id))?;
+        if hash != *piece_hash {
+            self.bad_peers.record(self.peer, piece_id);
+            return Err(HashMismatch(piece_id).into());
+        }
+        writer
+            .write_all(&piece)
+            .await
+            .map_err(|e| write_error(e, format_args!("piece {}", piece_id)))?;
+        writer
+            .flush()
+            .await
+            .map_err(|e| write_error(e, format_args!("piece {}", piece_id)))?;
+
+        self.pool.put(
+            self.peer,
+            Connection {
+                frame,
+                bitfield,
+                active,
+                keepalive_sent,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Request a block, sending the request again when the peer does not answer in time.
+    // All attempts together take about as long as the message timeout.
+    async fn request_block(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+        bitfield: &mut Bitfield,
+        request: Request,
+    ) -> anyhow::Result<Vec<u8>> {
+        let wait = self.timeouts.message / Self::BLOCK_ATTEMPTS;
+
+        for attempt in 1..=Self::BLOCK_ATTEMPTS {
             frame
                 .send(Message {
                     id: MessageType::Request,
@@ -112,181 +495,438 @@ impl Worker {
                 .await
                 .context("send request message")?;
 
-            let piece_msg = frame
-                .next()
+            match tokio::time::timeout(wait, self.next_requested_block(frame, bitfield, request))
                 .await
-                .expect("wait request response")
-                .context("invalid request response")?;
+            {
+                Ok(Ok(block)) => return Ok(block),
+                Ok(Err(e)) => {
+                    self.cancel(frame, request).await;
+                    return Err(e);
+                }
+                Err(_) => {
+                    debug!(
+                        "No answer for block {} of piece {}, attempt {}",
+                        request.begin, request.index, attempt
+                    );
+                    self.cancel(frame, request).await;
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "No answer for block {} of piece {} after {} attempts",
+            request.begin,
+            request.index,
+            Self::BLOCK_ATTEMPTS
+        ))
+    }
 
-            assert!(!piece_msg.payload.is_empty());
+    // Wait for the block answering `request`. Blocks for other offsets are late answers
+    // to requests given up on before, and are skipped.
+    async fn next_requested_block(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+        bitfield: &mut Bitfield,
+        request: Request,
+    ) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let piece_msg = self.next_block_message(frame, bitfield, None).await?;
+            // A choke drops the request, which is sent again once its attempt times out.
+            if piece_msg.id != MessageType::Piece {
+                continue;
+            }
 
             let piece_data = Piece::load_from_payload(&piece_msg.payload)
                 .ok_or(anyhow::anyhow!("Invalid piece from peer"))?;
-            assert_eq!(piece_data.index as usize, piece_id);
-            assert_eq!(piece_data.begin as usize, block * Self::BLOCK_SIZE);
-            assert_eq!(piece_data.piece.len(), block_size);
-
-            block_data.extend_from_slice(piece_data.piece);
+            if piece_data.index != request.index || piece_data.begin != request.begin {
+                debug!(
+                    "Skipping late block {} of piece {}",
+                    piece_data.begin, piece_data.index
+                );
+                continue;
+            }
+            validate_block(
+                &piece_data,
+                request.index as usize,
+                request.begin as usize,
+                request.length as usize,
+            )?;
+
+            return Ok(piece_data.piece.to_vec());
         }
-
-        assert_eq!(block_data.len(), piece_size);
-
-        // Check hash before writing data into file.
-        let mut hasher = Sha1::new();
-        hasher.update(&block_data);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("cannot get hash from block data");
-        let piece_hash = self.torrent.info.pieces.0[piece_id];
-        assert_eq!(hash, piece_hash);
-
-        Ok(block_data)
     }
 
     pub async fn download_queue(
         &self,
-        queue: PiecesQueue,
+        picker: PiecePicker,
         result: Sender<(usize, Vec<u8>)>,
     ) -> anyhow::Result<()> {
-        // first connect to a node
-        let stream = self.connect().await?;
-        let mut frame = self.init_frame(stream).await?;
-
-        let file_len = self
-            .torrent
-            .info
-            .file_length()
-            .ok_or(anyhow::anyhow!("Multifile not implemented yet"))?;
-
-        let num_pieces = self.torrent.info.pieces.num_pieces();
-
-        'start: loop {
-            // get piece
-            let Some(piece_i) = queue.take_piece() else {
-                println!("no more pieces, exiting");
-                // we are done no more pieces at this time
-                break;
-            };
+        if self.bad_peers.is_banned(self.peer) {
+            return Err(BannedPeer(self.peer).into());
+        }
 
-            println!("Downloading piece: {} ", piece_i);
+        // first connect to a node
+        let mut connection = self.open().await?;
+
+        // The picker counts this peer's pieces only while we are downloading from it.
+        picker.add_peer(&connection.bitfield);
+        let drained = self
+            .drain_queue(
+                &mut connection.frame,
+                &mut connection.bitfield,
+                &picker,
+                &result,
+            )
+            .await;
+        picker.remove_peer(&connection.bitfield);
+
+        // Keep a connection that is still healthy for the next time this peer is used.
+        if drained.is_ok() {
+            self.pool.put(self.peer, connection);
+        }
 
-            let piece_hash = self.torrent.info.pieces[piece_i];
+        drained
+    }
 
-            let piece_size = if piece_i == num_pieces - 1 {
-                // here we need to check if piece length is equal to max piece value, or not.
-                let base_len = file_len % self.torrent.info.plength;
-                if base_len == 0 {
-                    self.torrent.info.plength
-                } else {
-                    base_len
-                }
-            } else {
-                self.torrent.info.plength
-            };
+    // Download pieces from the peer until it has none left that we need, keeping up to
+    // PIPELINE_DEPTH block requests in flight. They span as many pieces as it takes, so
+    // a fast peer is never left idle between pieces, and each piece is delivered as soon
+    // as its last block is in. On error the unfinished pieces go back to the picker, only
+    // a corrupt piece counts as a failed download of it.
+    async fn drain_queue(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+        bitfield: &mut Bitfield,
+        picker: &PiecePicker,
+        result: &Sender<(usize, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let mut pieces = Vec::new();
+        let drained = self
+            .pipeline(frame, bitfield, picker, result, &mut pieces)
+            .await;
+        if drained.is_err() {
+            for piece in pieces {
+                picker.push_piece(piece.index);
+            }
+        }
+        drained
+    }
 
-            // we need to figure out what block it is, for that we have:
-            // piece_i
-            // num_pieces
-            // piece_size
-            // blocks start at 0, that it is why the -1,
-            // if piece_i = 0, them we will have 2B/1B -> 1 - 1 = 0;
-            let n_blocks = (piece_size + (Self::BLOCK_SIZE - 1)) / Self::BLOCK_SIZE;
-            let mut piece_data = Vec::with_capacity(piece_size);
-
-            // now need to set index, begin, length.
-            // index = piece_i
-            // begin would depend of n_block
-            for block in 0..n_blocks {
-                let block_size = if block == n_blocks - 1 {
-                    // it turns out this piece is equal to max_block_len?
-                    let base_len = piece_size % Self::BLOCK_SIZE;
-                    if base_len == 0 {
-                        Self::BLOCK_SIZE
-                    } else {
-                        base_len
+    async fn pipeline(
+        &self,
+        frame: &mut Framed<PeerStream, MessageFrame>,
+        bitfield: &mut Bitfield,
+        picker: &PiecePicker,
+        result: &Sender<(usize, Vec<u8>)>,
+        pieces: &mut Vec<PieceDownload>,
+    ) -> anyhow::Result<()> {
+        // Requests sent and not answered yet.
+        let mut requested: Vec<Request> = Vec::new();
+        let mut choked = false;
+
+        loop {
+            // Top up the pipeline with the next blocks of the pieces in progress, taking a
+            // new piece once every block of those is requested.
+            while !choked && requested.len() < Self::PIPELINE_DEPTH {
+                let Some(request) = pieces.iter_mut().find_map(PieceDownload::next_request) else {
+                    // Never hand the peer a piece it sent corrupt before.
+                    match picker.take_piece_in(&self.bad_peers.allowed(self.peer, bitfield)) {
+                        Some(piece_i) => {
+                            debug!("Downloading piece {}", piece_i);
+                            let piece_size = self.torrent.info.piece_size(piece_i);
+                            pieces.push(PieceDownload::new(piece_i, piece_size));
+                            continue;
+                        }
+                        None => break,
                     }
-                } else {
-                    Self::BLOCK_SIZE
-                };
-
-                let request = Request {
-                    index: piece_i as u32,
-                    begin: (block * Self::BLOCK_SIZE) as u32,
-                    length: block_size as u32,
                 };
 
-                if frame
+                self.limiter.acquire(request.length as usize).await;
+                frame
                     .send(Message {
                         id: MessageType::Request,
                         payload: request.as_bytes().to_vec(),
                     })
                     .await
-                    .is_err()
-                {
-                    queue.push_piece(piece_i);
-                    break 'start;
-                }
+                    .with_context(|| format!("send request for piece {}", request.index))?;
+                requested.push(request);
+            }
 
-                // now read response
-                let Some(Ok(piece)) = frame.next().await else {
-                    queue.push_piece(piece_i);
-                    break;
-                };
+            if requested.is_empty() && !choked {
+                debug!("No more pieces available from peer, exiting");
+                return Ok(());
+            }
 
-                if piece.payload.is_empty() {
-                    queue.push_piece(piece_i);
-                    break 'start;
+            let message = self
+                .next_block_message(frame, bitfield, Some(picker))
+                .await
+                .context("no response for requested blocks")?;
+            match message.id {
+                MessageType::Piece => {}
+                // A choking peer drops our requests, they are sent again once it unchokes.
+                MessageType::Choke => {
+                    debug!("Choked");
+                    choked = true;
+                    requested.clear();
+                    pieces.iter_mut().for_each(PieceDownload::rewind);
+                    continue;
                 }
-                // assert_eq!(piece.tag, Tag::Piece);
-
-                let Some(piece) = Piece::load_from_payload(&piece.payload) else {
-                    queue.push_piece(piece_i);
-                    break 'start;
-                };
-
-                if piece.index as usize != piece_i
-                    || piece.begin as usize != (block * Self::BLOCK_SIZE)
-                    || piece.piece.len() != block_size
-                {
-                    // we downloaded an invalid piece
-                    queue.push_piece(piece_i);
-                    break 'start;
+                MessageType::Unchoke => {
+                    choked = false;
+                    continue;
                 }
-
-                // now extend our data
-                piece_data.extend_from_slice(piece.piece);
-            }
-
-            if piece_data.len() != piece_size {
-                queue.push_piece(piece_i);
-                break 'start;
+                // Only expected right after the handshake, but the pieces it adds are usable.
+                MessageType::Bitfield => {
+                    let update = Bitfield::from_bytes(message.payload);
+                    let num_pieces = self.torrent.info.pieces.num_pieces();
+                    for piece_i in update.iter_ones().filter(|&i| i < num_pieces) {
+                        if !bitfield.has(piece_i) {
+                            bitfield.set(piece_i);
+                            picker.add_piece(piece_i);
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
             }
-
-            let mut hasher = Sha1::new();
-            hasher.update(&piece_data);
-
-            let hash: Result<[u8; 20], _> = hasher.finalize().try_into();
-            let Ok(hash) = hash else {
-                queue.push_piece(piece_i);
-                break 'start;
+            let block = Piece::load_from_payload(&message.payload)
+                .ok_or(anyhow::anyhow!("invalid response to block requests"))?;
+
+            let Some(position) = requested
+                .iter()
+                .position(|r| r.index == block.index && r.begin == block.begin)
+            else {
+                debug!(
+                    "Skipping unrequested block {} of piece {}",
+                    block.begin, block.index
+                );
+                continue;
             };
+            let request = requested.remove(position);
+            let position = pieces
+                .iter()
+                .position(|piece| piece.index == request.index as usize)
+                .expect("requested piece is in progress");
+            if let Err(e) = validate_block(
+                &block,
+                request.index as usize,
+                request.begin as usize,
+                request.length as usize,
+            ) {
+                picker.fail_piece(pieces.remove(position).index);
+                return Err(e);
+            }
+            pieces[position].add_block(request.begin as usize, block.piece);
+            if !pieces[position].is_complete() {
+                continue;
+            }
 
-            if hash != piece_hash {
-                queue.push_piece(piece_i);
-                break 'start;
+            // The piece stays in progress until it is verified, so a bad one is given back.
+            let (hash, piece_data) = pieces[position].finish();
+            let piece_i = pieces[position].index;
+            pieces.remove(position);
+            if hash != self.torrent.info.pieces[piece_i] {
+                picker.fail_piece(piece_i);
+                if self.bad_peers.record(self.peer, piece_i) {
+                    return Err(BannedPeer(self.peer).into());
+                }
+                return Err(HashMismatch(piece_i).into());
             }
 
             // This will errors only if receiver was closed before.
             // so no need to push unsuccesful piece id
             result.send((piece_i, piece_data)).await?;
+            debug!("Piece {} completed", piece_i);
         }
+    }
 
-        Ok(())
+    // Keep draining the queue from this peer, reconnecting with exponential backoff
+    // whenever the connection fails, until `max_attempts` connections have failed.
+    #[tracing::instrument(name = "peer", skip_all, fields(addr = %self.peer))]
+    pub async fn download_queue_with_retry(
+        &self,
+        picker: PiecePicker,
+        result: Sender<(usize, Vec<u8>)>,
+        max_attempts: usize,
+    ) -> anyhow::Result<()> {
+        let mut backoff = Self::RETRY_BACKOFF;
+        let mut attempt = 1;
+
+        loop {
+            match self.download_queue(picker.clone(), result.clone()).await {
+                Ok(()) if picker.is_empty() || result.is_closed() => return Ok(()),
+                // Pieces given back by failed peers may be ones this peer has,
+                // check again later over the pooled connection.
+                Ok(()) => tokio::time::sleep(Self::RETRY_BACKOFF).await,
+                // Another worker is downloading from this client already.
+                Err(e) if e.is::<DuplicatePeer>() => {
+                    debug!("Dropping duplicate peer: {}", e);
+                    return Err(e);
+                }
+                // The peer keeps sending corrupt pieces, there is no point in retrying.
+                Err(e) if e.is::<BannedPeer>() => {
+                    debug!("Banning peer: {}", e);
+                    return Err(e);
+                }
+                // Nobody is waiting for pieces anymore, so there is no point in retrying.
+                Err(e) if attempt >= max_attempts || result.is_closed() => {
+                    debug!("Giving up on peer: {}", e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    debug!("Peer failed: {}, reconnecting in {:?}", e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
     }
 }
 
-fn get_residual_size(index: usize, count: usize, length: usize, max_length: usize) -> usize {
+// A piece downloaded over a pipelined connection, whose blocks are requested in order
+// but may arrive interleaved with those of other pieces.
+struct PieceDownload {
+    index: usize,
+    data: Vec<u8>,
+    // How many of the first blocks are requested.
+    requested: usize,
+    received: Vec<bool>,
+    // Blocks are hashed as soon as every block before them is in, which with a peer
+    // answering in order is as they arrive.
+    hasher: Sha1,
+    hashed: usize,
+}
+
+impl PieceDownload {
+    fn new(index: usize, size: usize) -> Self {
+        let num_blocks = (size + (Worker::BLOCK_SIZE - 1)) / Worker::BLOCK_SIZE;
+        Self {
+            index,
+            data: vec![0; size],
+            requested: 0,
+            received: vec![false; num_blocks],
+            hasher: Sha1::new(),
+            hashed: 0,
+        }
+    }
+
+    // The request for the next block not asked for yet, if any.
+    fn next_request(&mut self) -> Option<Request> {
+        let num_blocks = self.received.len();
+        // After a rewind, blocks received already are not asked for again.
+        while self.requested < num_blocks && self.received[self.requested] {
+            self.requested += 1;
+        }
+        if self.requested == num_blocks {
+            return None;
+        }
+
+        let block = self.requested;
+        self.requested += 1;
+        Some(Request {
+            index: self.index as u32,
+            begin: (block * Worker::BLOCK_SIZE) as u32,
+            length: get_residual_size(block, num_blocks, self.data.len(), Worker::BLOCK_SIZE)
+                as u32,
+        })
+    }
+
+    // Store a block answering one of our requests for this piece.
+    fn add_block(&mut self, begin: usize, block: &[u8]) {
+        self.data[begin..begin + block.len()].copy_from_slice(block);
+        self.received[begin / Worker::BLOCK_SIZE] = true;
+
+        while self.received.get(self.hashed) == Some(&true) {
+            let start = self.hashed * Worker::BLOCK_SIZE;
+            let end = (start + Worker::BLOCK_SIZE).min(self.data.len());
+            self.hasher.update(&self.data[start..end]);
+            self.hashed += 1;
+        }
+    }
+
+    // Our requests were dropped: ask again for every block not received.
+    fn rewind(&mut self) {
+        self.requested = self.hashed;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.hashed == self.received.len()
+    }
+
+    // The hash and data of a complete piece.
+    fn finish(&mut self) -> ([u8; 20], Vec<u8>) {
+        let hasher = std::mem::take(&mut self.hasher);
+        (hasher.finalize().into(), std::mem::take(&mut self.data))
+    }
+}
+
+// A piece whose data did not match its hash.
+#[derive(Debug)]
+pub struct HashMismatch(pub usize);
+
+impl std::fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hash mismatch for piece {}", self.0)
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+// Check a received block matches the request we sent for it.
+fn validate_block(
+    piece: &Piece,
+    piece_id: usize,
+    begin: usize,
+    block_size: usize,
+) -> anyhow::Result<()> {
+    if piece.index as usize != piece_id {
+        return Err(anyhow::anyhow!(
+            "Unexpected piece index {} expected {}",
+            piece.index,
+            piece_id
+        ));
+    }
+    if piece.begin as usize != begin {
+        return Err(anyhow::anyhow!(
+            "Unexpected block begin {} expected {}",
+            piece.begin,
+            begin
+        ));
+    }
+    if piece.piece.len() != block_size {
+        return Err(anyhow::anyhow!(
+            "Unexpected block length {} expected {}",
+            piece.piece.len(),
+            block_size
+        ));
+    }
+
+    Ok(())
+}
+
+// A Have message carries the index of the piece the peer just got.
+fn have_index(payload: Vec<u8>, num_pieces: usize) -> anyhow::Result<usize> {
+    let index = Have::load_from_payload(&payload)
+        .ok_or(anyhow::anyhow!("Invalid have message from peer"))?
+        .index as usize;
+    if index >= num_pieces {
+        return Err(anyhow::anyhow!("Have message for unknown piece {}", index));
+    }
+    Ok(index)
+}
+
+// SHA-1 of a whole piece, to be compared against the torrent's piece hashes.
+pub fn hash_piece(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+// Size of item `index` out of `count` items splitting `length` bytes into `max_length` chunks:
+// `max_length`, except for a shorter last item when `length` is not a multiple of it.
+// Used for the blocks of a piece.
+pub fn get_residual_size(index: usize, count: usize, length: usize, max_length: usize) -> usize {
     if index == count - 1 && (length % max_length) != 0 {
         length % max_length
     } else {
@@ -294,24 +934,46 @@ fn get_residual_size(index: usize, count: usize, length: usize, max_length: usiz
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct PiecesQueue(Arc<Mutex<VecDeque<usize>>>);
-
-impl PiecesQueue {
-    pub fn new(pieces: Range<usize>) -> Self {
-        let queue = pieces.collect::<VecDeque<usize>>();
-        let pieces = Arc::new(Mutex::new(queue));
-        Self(pieces)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_block_rejects_wrong_begin() {
+        let data = [0u8; 16];
+        let piece = Piece {
+            index: 1,
+            begin: 16,
+            piece: &data,
+        };
+
+        assert!(validate_block(&piece, 1, 16, 16).is_ok());
+        assert!(validate_block(&piece, 1, 0, 16).is_err());
     }
 
-    pub fn take_piece(&self) -> Option<usize> {
-        self.0.lock().expect("PiecesQueue take piece").pop_front()
+    #[test]
+    fn residual_size_of_exact_multiples() {
+        const BLOCK: usize = Worker::BLOCK_SIZE;
+
+        // A single block, exactly full.
+        assert_eq!(get_residual_size(0, 1, BLOCK, BLOCK), BLOCK);
+
+        // Exactly three blocks, the last one full as well.
+        for block in 0..3 {
+            assert_eq!(get_residual_size(block, 3, 3 * BLOCK, BLOCK), BLOCK);
+        }
+
+        // A torrent whose last piece is exactly a piece length.
+        let plength = 4 * BLOCK;
+        assert_eq!(get_residual_size(4, 5, 5 * plength, plength), plength);
     }
 
-    pub fn push_piece(&self, piece: usize) {
-        self.0
-            .lock()
-            .expect("PiecesQueue push piece")
-            .push_back(piece)
+    #[test]
+    fn residual_size_of_short_last_item() {
+        const BLOCK: usize = Worker::BLOCK_SIZE;
+
+        assert_eq!(get_residual_size(0, 2, BLOCK + 10, BLOCK), BLOCK);
+        assert_eq!(get_residual_size(1, 2, BLOCK + 10, BLOCK), 10);
+        assert_eq!(get_residual_size(0, 1, 10, BLOCK), 10);
     }
 }
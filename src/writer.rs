@@ -0,0 +1,544 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use crate::bitfield::Bitfield;
+use crate::torrent::{Info, Keys};
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// Writes verified pieces straight to their place in the output files,
+// so only the set of completed pieces is kept in memory.
+//
+// A single-file torrent is written to the output path, a multi-file torrent
+// into the output directory with each file at its path from the torrent.
+//
+// The completed pieces are also persisted to a sidecar `<output>.part` file,
+// which lets an interrupted download be resumed.
+//
+// When only some files are selected, the others are never created: just the pieces
+// touching the selected files are wanted, and their data for other files is dropped.
+pub struct PieceWriter {
+    files: Vec<FileSpan>,
+    part_path: String,
+    piece_length: usize,
+    num_pieces: usize,
+    // The pieces holding data of the selected files.
+    wanted: Bitfield,
+    completed: Bitfield,
+}
+
+// A file on disk and the range of the torrent's data it holds.
+struct FileSpan {
+    file: File,
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+}
+
+impl PieceWriter {
+    // `select` lists the indices of the files to write, None writes all of them.
+    pub async fn create(path: &str, info: &Info, select: Option<&[usize]>) -> anyhow::Result<Self> {
+        let num_pieces = info.pieces.num_pieces();
+        let (selected, wanted) = selection(info, select)?;
+
+        Ok(Self {
+            files: open_files(path, info, &selected, true).await?,
+            part_path: part_path(path),
+            piece_length: info.plength,
+            num_pieces,
+            wanted,
+            completed: Bitfield::new(num_pieces),
+        })
+    }
+
+    // Reopen a partially downloaded output, keeping the pieces recorded in its part file
+    // that still verify against their hashes.
+    pub async fn resume(path: &str, info: &Info, select: Option<&[usize]>) -> anyhow::Result<Self> {
+        let num_pieces = info.pieces.num_pieces();
+        let part_path = part_path(path);
+        let (selected, wanted) = selection(info, select)?;
+
+        let recorded = match tokio::fs::read(&part_path).await {
+            Ok(bytes) => Bitfield::from_bytes(bytes),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Bitfield::new(num_pieces),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut writer = Self {
+            files: open_files(path, info, &selected, false).await?,
+            part_path,
+            piece_length: info.plength,
+            num_pieces,
+            wanted,
+            completed: Bitfield::new(num_pieces),
+        };
+
+        let mut piece = vec![0u8; info.plength];
+        for piece_i in recorded.iter_ones().filter(|&piece_i| piece_i < num_pieces) {
+            let offset = info.piece_offset(piece_i);
+            let piece_size = info.piece_size(piece_i);
+            // A piece partly in files that were not selected cannot be checked, fetch it again.
+            if !writer.covers(offset, piece_size) {
+                continue;
+            }
+
+            writer.read_at(offset, &mut piece[..piece_size]).await?;
+
+            let hash: [u8; 20] = Sha1::digest(&piece[..piece_size]).into();
+            if hash == info.pieces[piece_i] {
+                writer.completed.set(piece_i);
+            }
+        }
+        writer.save_part(&writer.completed).await?;
+
+        Ok(writer)
+    }
+
+    pub async fn write_piece(&mut self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        if piece_i >= self.num_pieces {
+            return Err(anyhow::anyhow!("Unexpected piece_i: {}", piece_i));
+        }
+        if self.completed.has(piece_i) {
+            return Err(anyhow::anyhow!("Unexpected repeated piece_i: {}", piece_i));
+        }
+
+        // Until the part file records it, the piece is not complete: the data written of a
+        // piece that failed is fetched and written again on resume.
+        self.write_at(piece_i * self.piece_length, data).await?;
+
+        let mut completed = self.completed.clone();
+        completed.set(piece_i);
+        self.save_part(&completed).await?;
+        self.completed = completed;
+
+        Ok(())
+    }
+
+    // The wanted pieces not written yet.
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.wanted()
+            .filter(|&piece_i| !self.completed.has(piece_i))
+    }
+
+    // The pieces on disk, counting those kept from before a resume.
+    pub fn completed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.completed.iter_ones()
+    }
+
+    pub fn wanted(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(|&piece_i| self.wanted.has(piece_i))
+    }
+
+    // The bytes of the missing pieces, announced to trackers as what is left.
+    pub fn bytes_left(&self, info: &Info) -> usize {
+        self.missing().map(|piece_i| info.piece_size(piece_i)).sum()
+    }
+
+    // The bytes of the completed pieces, so a resumed download is not announced as starting over.
+    pub fn bytes_completed(&self, info: &Info) -> usize {
+        self.completed()
+            .map(|piece_i| info.piece_size(piece_i))
+            .sum()
+    }
+
+    // Whether the files we write hold all of `offset..offset + len`.
+    fn covers(&self, offset: usize, len: usize) -> bool {
+        let covered: usize = self
+            .files
+            .iter()
+            .filter_map(|span| span.overlap(offset, len))
+            .map(|(start, end)| end - start)
+            .sum();
+        covered == len
+    }
+
+    // Write data starting at `offset` of the torrent, split across the files it covers.
+    async fn write_at(&mut self, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        for span in &mut self.files {
+            let Some((start, end)) = span.overlap(offset, data.len()) else {
+                continue;
+            };
+
+            let path = &span.path;
+            span.file
+                .seek(SeekFrom::Start((start - span.offset) as u64))
+                .await?;
+            span.file
+                .write_all(&data[start - offset..end - offset])
+                .await
+                .map_err(|e| write_error(e, path.display()))?;
+            // Only record the piece once its data reached the file.
+            span.file
+                .flush()
+                .await
+                .map_err(|e| write_error(e, path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    // Read into buf starting at `offset` of the torrent, gathered from the files it covers.
+    async fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        let len = buf.len();
+        for span in &mut self.files {
+            let Some((start, end)) = span.overlap(offset, len) else {
+                continue;
+            };
+
+            span.file
+                .seek(SeekFrom::Start((start - span.offset) as u64))
+                .await?;
+            span.file
+                .read_exact(&mut buf[start - offset..end - offset])
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // The part file is overwritten in place rather than replaced. It keeps its size, so
+    // on a full disk it still holds the pieces recorded last instead of nothing.
+    async fn save_part(&self, completed: &Bitfield) -> anyhow::Result<()> {
+        let path = Path::new(&self.part_path);
+        let bytes = completed.as_bytes();
+        let mut part = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await
+            .with_context(|| format!("open {}", path.display()))?;
+        part.write_all(bytes)
+            .await
+            .map_err(|e| write_error(e, path.display()))?;
+        // Writes are only reported to have failed by the flush.
+        part.flush()
+            .await
+            .map_err(|e| write_error(e, path.display()))?;
+        part.set_len(bytes.len() as u64).await?;
+        Ok(())
+    }
+
+    // Flush the files, failing with the missing indices if any piece was never written.
+    // The part file is no longer needed once everything is in.
+    pub async fn finish(mut self) -> anyhow::Result<()> {
+        let missing = self
+            .missing()
+            .map(|piece_i| piece_i.to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Missing pieces got: {} but require: {}, missing: {}",
+                self.completed.count_ones(),
+                self.wanted.count_ones(),
+                missing.join(", ")
+            ));
+        }
+
+        for span in &mut self.files {
+            span.file.flush().await?;
+        }
+        tokio::fs::remove_file(&self.part_path).await?;
+
+        Ok(())
+    }
+}
+
+impl FileSpan {
+    // The part of `offset..offset + len` held by this file, as torrent offsets.
+    fn overlap(&self, offset: usize, len: usize) -> Option<(usize, usize)> {
+        let start = offset.max(self.offset);
+        let end = (offset + len).min(self.offset + self.length);
+        (start < end).then_some((start, end))
+    }
+}
+
+// Which files are selected, in `files` order, and the pieces needed to write them.
+fn selection(info: &Info, select: Option<&[usize]>) -> anyhow::Result<(Vec<bool>, Bitfield)> {
+    let num_files = info.file_spans().len();
+    let selected = match select {
+        None => vec![true; num_files],
+        Some(select) => {
+            let mut selected = vec![false; num_files];
+            for &file_i in select {
+                *selected.get_mut(file_i).ok_or(anyhow::anyhow!(
+                    "No file {}, the torrent has {} files",
+                    file_i,
+                    num_files
+                ))? = true;
+            }
+            selected
+        }
+    };
+
+    let mut wanted = Bitfield::new(info.pieces.num_pieces());
+    for file_i in (0..num_files).filter(|&file_i| selected[file_i]) {
+        for piece_i in info.file_pieces(file_i).unwrap_or_default() {
+            wanted.set(piece_i);
+        }
+    }
+
+    Ok((selected, wanted))
+}
+
+// Open (or create) every selected file the torrent's data goes to, pre-allocated to its
+// full length so pieces can land at any offset.
+async fn open_files(
+    path: &str,
+    info: &Info,
+    selected: &[bool],
+    truncate: bool,
+) -> anyhow::Result<Vec<FileSpan>> {
+    let layout: Vec<(PathBuf, usize)> = match &info.keys {
+        Keys::SingleFile { length } => vec![(PathBuf::from(path), *length)],
+        Keys::MultiFile { files } => {
+            let base = Path::new(path);
+            if base.is_file() {
+                return Err(anyhow::anyhow!(
+                    "Output {} is a file, but a multi-file torrent needs a directory",
+                    path
+                ));
+            }
+
+            let mut layout = Vec::with_capacity(files.len());
+            for (file, &selected) in files.iter().zip(selected) {
+                let local = file.local_path(base)?;
+                if selected {
+                    if let Some(parent) = local.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                }
+                layout.push((local, file.length));
+            }
+            layout
+        }
+    };
+
+    let mut files = Vec::with_capacity(layout.len());
+    let mut offset = 0;
+    for ((path, length), &selected) in layout.into_iter().zip(selected) {
+        // The data of a file left out still takes its place in the torrent.
+        if !selected {
+            offset += length;
+            continue;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(&path)
+            .await
+            .with_context(|| format!("open {}", path.display()))?;
+        file.set_len(length as u64).await?;
+
+        files.push(FileSpan {
+            file,
+            path,
+            offset,
+            length,
+        });
+        offset += length;
+    }
+
+    Ok(files)
+}
+
+fn part_path(path: &str) -> String {
+    format!("{}.part", path)
+}
+
+// A write failed because the disk is full. Every piece recorded in the part file is
+// on disk, so once there is space again the download can be resumed.
+#[derive(Debug)]
+pub struct DiskFull(pub String);
+
+impl std::fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Out of disk space writing {}", self.0)
+    }
+}
+
+impl std::error::Error for DiskFull {}
+
+// Whether the error is the disk being full: ENOSPC, or ERROR_HANDLE_DISK_FULL and
+// ERROR_DISK_FULL on Windows. ErrorKind::StorageFull needs a newer toolchain.
+pub fn is_disk_full(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: &[i32] = &[28];
+    #[cfg(windows)]
+    const CODES: &[i32] = &[39, 112];
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+
+    e.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+// An error writing `what`, as DiskFull when that is the reason.
+pub fn write_error(e: std::io::Error, what: impl std::fmt::Display) -> anyhow::Error {
+    if is_disk_full(&e) {
+        DiskFull(what.to_string()).into()
+    } else {
+        anyhow::Error::new(e).context(format!("write {}", what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::create_torrent;
+    use std::io::Write;
+
+    // The info of a single-file torrent of `length` bytes of made-up data, with the data.
+    fn synthetic_info(length: usize, piece_length: usize) -> (Info, Vec<u8>) {
+        let data: Vec<u8> = (0..length).map(|i| (i * 7 % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+        let torrent = create_torrent(
+            file.path(),
+            "http://127.0.0.1/announce".into(),
+            piece_length,
+        )
+        .unwrap();
+        (torrent.info, data)
+    }
+
+    fn piece(info: &Info, data: &[u8], piece_i: usize) -> Vec<u8> {
+        let offset = info.piece_offset(piece_i);
+        data[offset..offset + info.piece_size(piece_i)].to_vec()
+    }
+
+    #[tokio::test]
+    async fn write_pieces_out_of_order() {
+        // The last piece is shorter.
+        let (info, data) = synthetic_info(2 * 1024 + 500, 1024);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").display().to_string();
+
+        let mut writer = PieceWriter::create(&output, &info, None).await.unwrap();
+        for piece_i in [2, 0, 1] {
+            writer
+                .write_piece(piece_i, &piece(&info, &data, piece_i))
+                .await
+                .unwrap();
+        }
+        assert_eq!(writer.completed().collect::<Vec<_>>(), [0, 1, 2]);
+        writer.finish().await.unwrap();
+
+        assert_eq!(tokio::fs::read(&output).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn bytes_left_after_resume() {
+        // Four pieces, the last one 300 bytes.
+        let (info, data) = synthetic_info(3 * 1024 + 300, 1024);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").display().to_string();
+
+        let mut writer = PieceWriter::create(&output, &info, None).await.unwrap();
+        assert_eq!(writer.bytes_left(&info), 3 * 1024 + 300);
+        for piece_i in [1, 3] {
+            writer
+                .write_piece(piece_i, &piece(&info, &data, piece_i))
+                .await
+                .unwrap();
+        }
+        drop(writer);
+
+        let writer = PieceWriter::resume(&output, &info, None).await.unwrap();
+        assert_eq!(writer.bytes_left(&info), 2 * 1024);
+        assert_eq!(writer.bytes_completed(&info), 1024 + 300);
+    }
+
+    #[tokio::test]
+    async fn finish_names_missing_pieces() {
+        let (info, data) = synthetic_info(3 * 1024, 1024);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").display().to_string();
+
+        let mut writer = PieceWriter::create(&output, &info, None).await.unwrap();
+        for piece_i in [0, 2] {
+            writer
+                .write_piece(piece_i, &piece(&info, &data, piece_i))
+                .await
+                .unwrap();
+        }
+        let part_path = writer.part_path.clone();
+
+        let err = writer.finish().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Missing pieces got: 2 but require: 3, missing: 1"
+        );
+        // The download can still be resumed.
+        assert!(std::path::Path::new(&part_path).exists());
+    }
+
+    // /dev/full fails every write with ENOSPC.
+    #[cfg(target_os = "linux")]
+    async fn full_disk() -> File {
+        OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .await
+            .unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn full_disk_writing_data_keeps_recorded_pieces() {
+        let (info, data) = synthetic_info(3 * 1024, 1024);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").display().to_string();
+
+        let mut writer = PieceWriter::create(&output, &info, None).await.unwrap();
+        writer
+            .write_piece(0, &piece(&info, &data, 0))
+            .await
+            .unwrap();
+        writer.files[0].file = full_disk().await;
+
+        let err = writer
+            .write_piece(1, &piece(&info, &data, 1))
+            .await
+            .unwrap_err();
+        assert!(err.is::<DiskFull>(), "{}", err);
+        assert_eq!(writer.completed().collect::<Vec<_>>(), [0]);
+        drop(writer);
+
+        let writer = PieceWriter::resume(&output, &info, None).await.unwrap();
+        assert_eq!(writer.completed().collect::<Vec<_>>(), [0]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn full_disk_saving_part_file_keeps_recorded_pieces() {
+        let (info, data) = synthetic_info(3 * 1024, 1024);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").display().to_string();
+
+        let mut writer = PieceWriter::create(&output, &info, None).await.unwrap();
+        writer
+            .write_piece(0, &piece(&info, &data, 0))
+            .await
+            .unwrap();
+        writer.part_path = "/dev/full".into();
+
+        let err = writer
+            .write_piece(1, &piece(&info, &data, 1))
+            .await
+            .unwrap_err();
+        assert!(err.is::<DiskFull>(), "{}", err);
+        assert_eq!(err.to_string(), "Out of disk space writing /dev/full");
+        // Piece 1 reached the file but was never recorded, so it is fetched again.
+        assert_eq!(writer.completed().collect::<Vec<_>>(), [0]);
+        drop(writer);
+
+        let writer = PieceWriter::resume(&output, &info, None).await.unwrap();
+        assert_eq!(writer.completed().collect::<Vec<_>>(), [0]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::handshake::{Handshake, Timeouts};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+const CLIENT_ID: [u8; 20] = *b"-TS0001-000000000000";
+
+#[tokio::test]
+async fn handshake_with_silent_peer_times_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Accept the connection and hold it open without ever answering.
+    tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let mut handshake = Handshake::new(INFO_HASH, CLIENT_ID);
+    handshake.timeouts = Timeouts {
+        connect: Duration::from_millis(200),
+        message: Duration::from_millis(200),
+    };
+    let start = Instant::now();
+    let err = match handshake.send(addr).await {
+        Ok(_) => panic!("handshake with a silent peer succeeded"),
+        Err(e) => e,
+    };
+
+    assert!(err.to_string().contains("timed out"), "{}", err);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn handshake_with_wrong_protocol_is_rejected() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Answer with our own handshake, but for another protocol of the same length.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        handshake[1..20].copy_from_slice(b"BitTorrent protocoX");
+        stream.write_all(&handshake).await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let mut handshake = Handshake::new(INFO_HASH, CLIENT_ID);
+    let err = match handshake.send(addr).await {
+        Ok(_) => panic!("handshake with the wrong protocol succeeded"),
+        Err(e) => e,
+    };
+
+    assert!(err.to_string().contains("Unexpected protocol"), "{}", err);
+}
@@ -0,0 +1,613 @@
+// An in-process peer to run the peer protocol against, without the network.
+//
+// It answers the handshake, sends its bitfield, unchokes as soon as we are interested
+// and serves requested blocks from the torrent's data.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::handshake::{Handshake, Timeouts};
+use bittorrent_starter_rust::peer::{Have, Message, MessageFrame, MessageType, Piece, Request};
+use bittorrent_starter_rust::picker::{PickMode, PiecePicker};
+use bittorrent_starter_rust::pool::{BadPeers, BannedPeer};
+use bittorrent_starter_rust::torrent::{create_torrent, Torrent};
+use bittorrent_starter_rust::worker::{HashMismatch, Worker};
+use bittorrent_starter_rust::writer::DiskFull;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+const PEER_ID: [u8; 20] = *b"-MK0001-000000000000";
+const CLIENT_ID: [u8; 20] = *b"-TS0001-000000000000";
+
+struct MockPeer {
+    torrent: Arc<Torrent>,
+    data: Arc<Vec<u8>>,
+    // The pieces advertised and served.
+    bitfield: Bitfield,
+    // The pieces served with every byte flipped, so they fail their hash check.
+    corrupt: Vec<usize>,
+    // Choke us once after serving this many blocks, dropping the requests that arrive
+    // until it unchokes us again.
+    choke_after: Option<usize>,
+    // Hold back the requests until no more arrive, then answer the last one first.
+    reversed: bool,
+    // Pieces announced with Have messages ahead of the bitfield, which leaves them out.
+    haves: Vec<usize>,
+    // Leave the first request for the block at this offset unanswered.
+    ignore_first: Option<u32>,
+}
+
+impl MockPeer {
+    // A peer with every piece of `torrent`, whose content is `data`.
+    fn new(torrent: Arc<Torrent>, data: Vec<u8>) -> Self {
+        let num_pieces = torrent.info.pieces.num_pieces();
+        let mut bitfield = Bitfield::new(num_pieces);
+        for piece_i in 0..num_pieces {
+            bitfield.set(piece_i);
+        }
+
+        Self {
+            torrent,
+            data: Arc::new(data),
+            bitfield,
+            corrupt: Vec::new(),
+            choke_after: None,
+            reversed: false,
+            haves: Vec::new(),
+            ignore_first: None,
+        }
+    }
+
+    fn with_pieces(mut self, pieces: &[usize]) -> Self {
+        self.bitfield = Bitfield::new(self.torrent.info.pieces.num_pieces());
+        for &piece_i in pieces {
+            self.bitfield.set(piece_i);
+        }
+        self
+    }
+
+    fn with_corrupt_pieces(mut self, pieces: &[usize]) -> Self {
+        self.corrupt = pieces.to_vec();
+        self
+    }
+
+    fn with_choke_after(mut self, blocks: usize) -> Self {
+        self.choke_after = Some(blocks);
+        self
+    }
+
+    fn with_reversed_answers(mut self) -> Self {
+        self.reversed = true;
+        self
+    }
+
+    fn with_haves_before_bitfield(mut self, pieces: &[usize]) -> Self {
+        self.haves = pieces.to_vec();
+        self
+    }
+
+    fn with_first_request_ignored(mut self, begin: u32) -> Self {
+        self.ignore_first = Some(begin);
+        self
+    }
+
+    // Listen on a free local port, serving every connection until the test ends.
+    async fn spawn(self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = Arc::new(self);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let peer = peer.clone();
+                tokio::spawn(async move { peer.serve(stream).await });
+            }
+        });
+
+        addr
+    }
+
+    async fn serve(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let mut handshake = Handshake::new(self.torrent.info_hash()?, PEER_ID);
+        handshake.accept(&mut stream).await?;
+
+        let mut frame = Framed::new(stream, MessageFrame);
+        for &piece_i in &self.haves {
+            frame
+                .send(Message {
+                    id: MessageType::Have,
+                    payload: Have {
+                        index: piece_i as u32,
+                    }
+                    .as_bytes()
+                    .to_vec(),
+                })
+                .await?;
+        }
+        frame
+            .send(Message {
+                id: MessageType::Bitfield,
+                payload: self.bitfield.as_bytes().to_vec(),
+            })
+            .await?;
+
+        let mut choke_after = self.choke_after;
+        let mut ignore_first = self.ignore_first;
+        let mut held = Vec::new();
+        loop {
+            let message = if held.is_empty() {
+                frame.next().await
+            } else {
+                match tokio::time::timeout(Duration::from_millis(50), frame.next()).await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        for request in held.drain(..).rev() {
+                            frame.send(self.answer(request)).await?;
+                        }
+                        continue;
+                    }
+                }
+            };
+            let Some(message) = message else {
+                break;
+            };
+            let message = message?;
+            match message.id {
+                MessageType::Interested => {
+                    frame
+                        .send(Message {
+                            id: MessageType::Unchoke,
+                            payload: Vec::new(),
+                        })
+                        .await?;
+                }
+                MessageType::Request => {
+                    let request = Request::load_from_payload(&message.payload)
+                        .ok_or(anyhow::anyhow!("Invalid request"))?;
+                    let piece_i = request.index as usize;
+                    if !self.bitfield.has(piece_i) && !self.haves.contains(&piece_i) {
+                        return Err(anyhow::anyhow!("Request for a missing piece"));
+                    }
+                    if ignore_first == Some(request.begin) {
+                        ignore_first = None;
+                        continue;
+                    }
+                    if self.reversed {
+                        held.push(request);
+                        continue;
+                    }
+                    frame.send(self.answer(request)).await?;
+
+                    choke_after = choke_after.map(|blocks| blocks - 1);
+                    if choke_after == Some(0) {
+                        choke_after = None;
+                        self.choke(&mut frame).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn answer(&self, request: Request) -> Message {
+        let offset =
+            self.torrent.info.piece_offset(request.index as usize) + request.begin as usize;
+        let mut block = self.data[offset..offset + request.length as usize].to_vec();
+        if self.corrupt.contains(&(request.index as usize)) {
+            block.iter_mut().for_each(|b| *b = !*b);
+        }
+        let piece = Piece {
+            index: request.index,
+            begin: request.begin,
+            piece: &block,
+        };
+        Message {
+            id: MessageType::Piece,
+            payload: piece.as_bytes(),
+        }
+    }
+
+    async fn choke(&self, frame: &mut Framed<TcpStream, MessageFrame>) -> anyhow::Result<()> {
+        frame
+            .send(Message {
+                id: MessageType::Choke,
+                payload: Vec::new(),
+            })
+            .await?;
+        let until = tokio::time::Instant::now() + Duration::from_millis(100);
+        while let Ok(Some(message)) = tokio::time::timeout_at(until, frame.next()).await {
+            message?;
+        }
+        frame
+            .send(Message {
+                id: MessageType::Unchoke,
+                payload: Vec::new(),
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+// A single-file torrent of `length` bytes of made-up data, along with the data.
+fn synthetic_torrent(length: usize, piece_length: usize) -> (Arc<Torrent>, Vec<u8>) {
+    let data: Vec<u8> = (0..length).map(|i| (i * 7 % 251) as u8).collect();
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&data).unwrap();
+    let torrent = create_torrent(
+        file.path(),
+        "http://127.0.0.1/announce".into(),
+        piece_length,
+    )
+    .unwrap();
+
+    (Arc::new(torrent), data)
+}
+
+#[tokio::test]
+async fn download_pieces_from_mock_peer() {
+    // A full first piece of two blocks and a shorter last piece.
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data.clone()).spawn().await;
+
+    let worker = Worker::new(torrent.clone(), addr, CLIENT_ID);
+    for piece_i in 0..2 {
+        let piece = worker.download_piece(piece_i).await.unwrap();
+
+        let offset = torrent.info.piece_offset(piece_i);
+        let size = torrent.info.piece_size(piece_i);
+        assert_eq!(piece, data[offset..offset + size]);
+    }
+}
+
+#[tokio::test]
+async fn download_piece_to_a_vec() {
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data.clone()).spawn().await;
+
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let mut piece = Vec::new();
+    worker.download_piece_to(1, &mut piece).await.unwrap();
+    assert_eq!(piece, data[32_768..]);
+
+    // Nothing of a corrupt piece reaches the writer.
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data)
+        .with_corrupt_pieces(&[1])
+        .spawn()
+        .await;
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let mut piece = Vec::new();
+    let err = worker.download_piece_to(1, &mut piece).await.unwrap_err();
+    assert!(err.is::<HashMismatch>(), "{}", err);
+    assert!(piece.is_empty());
+}
+
+#[tokio::test]
+async fn haves_before_bitfield_are_kept() {
+    // Four pieces, the peer announcing 1 and 3 ahead of a bitfield with only 0.
+    let (torrent, data) = synthetic_torrent(4 * 32_768, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data)
+        .with_pieces(&[0])
+        .with_haves_before_bitfield(&[1, 3])
+        .spawn()
+        .await;
+
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let (stream, _) = worker.connect().await.unwrap();
+    let (_frame, bitfield) = worker.init_frame(stream).await.unwrap();
+    assert_eq!(bitfield.iter_ones().collect::<Vec<_>>(), [0, 1, 3]);
+
+    let picker = PiecePicker::new(0..4, 4, PickMode::Fifo);
+    picker.add_peer(&bitfield);
+    assert_eq!(picker.availability(), [1, 1, 0, 1]);
+}
+
+#[tokio::test]
+async fn unanswered_block_request_is_sent_again() {
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data.clone())
+        .with_first_request_ignored(16_384)
+        .spawn()
+        .await;
+
+    // Each of the three attempts at a block waits 200ms.
+    let worker = Worker::new(torrent.clone(), addr, CLIENT_ID).with_timeouts(Timeouts {
+        connect: Duration::from_secs(1),
+        message: Duration::from_millis(600),
+    });
+    let start = Instant::now();
+    let piece = worker.download_piece(0).await.unwrap();
+
+    assert_eq!(piece, data[..torrent.info.piece_size(0)]);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn peer_closing_after_handshake() {
+    let (torrent, _) = synthetic_torrent(40_000, 32_768);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash().unwrap();
+    // Close our side right after the handshake, reading whatever arrives so the
+    // connection ends with a plain end of stream rather than a reset.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        Handshake::new(info_hash, PEER_ID)
+            .accept(&mut stream)
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        tokio::io::copy(&mut stream, &mut tokio::io::sink())
+            .await
+            .unwrap();
+    });
+
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let (stream, _) = worker.connect().await.unwrap();
+    let err = match worker.init_frame(stream).await {
+        Ok(_) => panic!("init_frame with a closed peer succeeded"),
+        Err(e) => e,
+    };
+    assert_eq!(err.to_string(), "Peer closed before sending its bitfield");
+}
+
+#[tokio::test(start_paused = true)]
+async fn keepalive_sent_while_waiting_for_unchoke() {
+    let (torrent, _) = synthetic_torrent(40_000, 32_768);
+    let (ours, mut theirs) = tokio::io::duplex(1024);
+    let worker = Worker::new(torrent, "127.0.0.1:6881".parse().unwrap(), CLIENT_ID).with_timeouts(
+        Timeouts {
+            connect: Duration::from_secs(10),
+            message: Duration::from_secs(30),
+        },
+    );
+    let start = tokio::time::Instant::now();
+    let waiting = tokio::spawn(async move { worker.init_frame(Box::new(ours)).await.map(|_| ()) });
+
+    let mut interested = [0u8; 5];
+    theirs.read_exact(&mut interested).await.unwrap();
+    assert_eq!(interested, [0, 0, 0, 1, MessageType::Interested.id()]);
+
+    // The peer never answers: a keepalive goes out halfway through the message timeout.
+    let mut keepalive = [0xffu8; 4];
+    theirs.read_exact(&mut keepalive).await.unwrap();
+    assert_eq!(keepalive, [0; 4]);
+    assert_eq!(start.elapsed(), Duration::from_secs(15));
+
+    let err = waiting.await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{}", err);
+}
+
+#[tokio::test]
+async fn download_piece_the_peer_does_not_have() {
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data)
+        .with_pieces(&[0])
+        .spawn()
+        .await;
+
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let err = worker.download_piece(1).await.unwrap_err();
+    assert!(err.to_string().contains("does not have piece 1"), "{}", err);
+}
+
+#[tokio::test]
+async fn corrupt_piece_is_not_given_to_its_peer_again() {
+    // Four pieces of two blocks each.
+    let (torrent, data) = synthetic_torrent(4 * 32_768, 32_768);
+    let bad = MockPeer::new(torrent.clone(), data.clone())
+        .with_corrupt_pieces(&[0])
+        .spawn()
+        .await;
+    let good = MockPeer::new(torrent.clone(), data.clone()).spawn().await;
+
+    let bad_peers = BadPeers::default();
+    let picker = PiecePicker::new(0..4, 4, PickMode::Fifo);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+
+    let worker = Worker::new(torrent.clone(), bad, CLIENT_ID).with_bad_peers(bad_peers.clone());
+    let err = worker
+        .download_queue(picker.clone(), tx.clone())
+        .await
+        .unwrap_err();
+    assert!(err.is::<HashMismatch>(), "{}", err);
+
+    // Once reconnected the peer gets every piece but the one it corrupted.
+    worker
+        .download_queue(picker.clone(), tx.clone())
+        .await
+        .unwrap();
+    assert!(!picker.is_empty());
+
+    let worker = Worker::new(torrent.clone(), good, CLIENT_ID).with_bad_peers(bad_peers);
+    worker.download_queue(picker.clone(), tx).await.unwrap();
+    assert!(picker.is_empty());
+
+    let mut pieces = Vec::new();
+    while let Some((piece_i, piece)) = rx.recv().await {
+        let offset = torrent.info.piece_offset(piece_i);
+        assert_eq!(piece, data[offset..offset + piece.len()]);
+        pieces.push(piece_i);
+    }
+    pieces.sort();
+    assert_eq!(pieces, [0, 1, 2, 3]);
+}
+
+#[tokio::test]
+async fn peer_sending_corrupt_pieces_is_banned() {
+    let (torrent, data) = synthetic_torrent(4 * 32_768, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data)
+        .with_corrupt_pieces(&[0, 1, 2, 3])
+        .spawn()
+        .await;
+
+    let bad_peers = BadPeers::default();
+    let picker = PiecePicker::new(0..4, 4, PickMode::Fifo);
+    let (tx, _rx) = tokio::sync::mpsc::channel(8);
+
+    let worker = Worker::new(torrent, addr, CLIENT_ID).with_bad_peers(bad_peers.clone());
+    for _ in 0..BadPeers::MAX_BAD_PIECES {
+        assert!(!bad_peers.is_banned(addr));
+        worker
+            .download_queue(picker.clone(), tx.clone())
+            .await
+            .unwrap_err();
+    }
+    assert!(bad_peers.is_banned(addr));
+
+    let err = worker.download_queue(picker, tx).await.unwrap_err();
+    assert!(err.is::<BannedPeer>(), "{}", err);
+}
+
+#[tokio::test]
+async fn pieces_answered_out_of_order() {
+    // Three pieces of two blocks each, all requested at once and answered last first.
+    let (torrent, data) = synthetic_torrent(3 * 32_768, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data.clone())
+        .with_reversed_answers()
+        .spawn()
+        .await;
+
+    let picker = PiecePicker::new(0..3, 3, PickMode::Fifo).with_max_failures(1);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    let worker = Worker::new(torrent.clone(), addr, CLIENT_ID);
+    worker.download_queue(picker.clone(), tx).await.unwrap();
+    assert!(picker.is_empty());
+
+    let mut pieces = Vec::new();
+    while let Some((piece_i, piece)) = rx.recv().await {
+        let offset = torrent.info.piece_offset(piece_i);
+        assert_eq!(piece.len(), torrent.info.piece_size(piece_i));
+        assert_eq!(piece, data[offset..offset + piece.len()]);
+        pieces.push(piece_i);
+    }
+    // The last piece is the first one complete.
+    assert_eq!(pieces, [2, 1, 0]);
+}
+
+#[tokio::test]
+async fn piece_failing_its_hash_is_given_up() {
+    let (torrent, data) = synthetic_torrent(2 * 32_768, 32_768);
+    let bad_peers = BadPeers::default();
+    let picker = PiecePicker::new(0..2, 2, PickMode::Fifo).with_max_failures(2);
+    let (tx, _rx) = tokio::sync::mpsc::channel(8);
+
+    // Each peer sends piece 0 corrupt once, and is not given it again.
+    for attempt in 1..=2 {
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), picker.given_up())
+                .await
+                .is_err(),
+            "given up before attempt {}",
+            attempt
+        );
+
+        let addr = MockPeer::new(torrent.clone(), data.clone())
+            .with_corrupt_pieces(&[0])
+            .spawn()
+            .await;
+        let worker =
+            Worker::new(torrent.clone(), addr, CLIENT_ID).with_bad_peers(bad_peers.clone());
+        let err = worker
+            .download_queue(picker.clone(), tx.clone())
+            .await
+            .unwrap_err();
+        assert!(err.is::<HashMismatch>(), "{}", err);
+    }
+
+    let given_up = tokio::time::timeout(Duration::from_secs(1), picker.given_up())
+        .await
+        .unwrap();
+    assert_eq!(given_up, 0);
+}
+
+#[tokio::test]
+async fn choke_while_downloading_is_not_a_failure() {
+    let (torrent, data) = synthetic_torrent(4 * 32_768, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data.clone())
+        .with_choke_after(3)
+        .spawn()
+        .await;
+
+    // A single failure would give the piece up.
+    let picker = PiecePicker::new(0..4, 4, PickMode::Fifo).with_max_failures(1);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    let worker = Worker::new(torrent.clone(), addr, CLIENT_ID);
+    worker.download_queue(picker.clone(), tx).await.unwrap();
+    assert!(picker.is_empty());
+
+    let mut pieces = Vec::new();
+    while let Some((piece_i, piece)) = rx.recv().await {
+        let offset = torrent.info.piece_offset(piece_i);
+        assert_eq!(piece, data[offset..offset + piece.len()]);
+        pieces.push(piece_i);
+    }
+    pieces.sort();
+    assert_eq!(pieces, [0, 1, 2, 3]);
+}
+
+// A writer that fails like a full disk once `capacity` bytes were written to it.
+#[cfg(unix)]
+struct FullDisk {
+    written: Vec<u8>,
+    capacity: usize,
+}
+
+#[cfg(unix)]
+impl tokio::io::AsyncWrite for FullDisk {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let room = self.capacity - self.written.len();
+        if room == 0 {
+            // ENOSPC
+            return std::task::Poll::Ready(Err(std::io::Error::from_raw_os_error(28)));
+        }
+        let n = buf.len().min(room);
+        self.written.extend_from_slice(&buf[..n]);
+        std::task::Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn download_piece_to_a_full_disk() {
+    let (torrent, data) = synthetic_torrent(40_000, 32_768);
+    let addr = MockPeer::new(torrent.clone(), data).spawn().await;
+
+    // Room for part of the piece only.
+    let mut disk = FullDisk {
+        written: Vec::new(),
+        capacity: 20_000,
+    };
+    let worker = Worker::new(torrent, addr, CLIENT_ID);
+    let err = worker.download_piece_to(0, &mut disk).await.unwrap_err();
+
+    assert!(err.is::<DiskFull>(), "{}", err);
+    assert_eq!(err.to_string(), "Out of disk space writing piece 0");
+    assert_eq!(disk.written.len(), 20_000);
+}
@@ -0,0 +1,55 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bittorrent_starter_rust::handshake::Handshake;
+use bittorrent_starter_rust::peer::{Have, MessageFrame, MessageType};
+use bittorrent_starter_rust::seed::Seeder;
+use bittorrent_starter_rust::torrent::create_torrent;
+use futures_util::StreamExt;
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+const SEEDER_ID: [u8; 20] = *b"-SD0001-000000000000";
+const CLIENT_ID: [u8; 20] = *b"-TS0001-000000000000";
+
+#[tokio::test]
+async fn connected_peer_receives_have_for_added_piece() {
+    // Two pieces, the second one not downloaded yet.
+    let data: Vec<u8> = (0..2 * 32_768).map(|i| (i * 7 % 251) as u8).collect();
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&data).unwrap();
+    let torrent =
+        Arc::new(create_torrent(file.path(), "http://127.0.0.1/announce".into(), 32_768).unwrap());
+    let mut partial = data.clone();
+    partial[32_768..].fill(0);
+    std::fs::write(file.path(), &partial).unwrap();
+
+    let seeder = Arc::new(
+        Seeder::new(torrent.clone(), file.path().to_path_buf(), SEEDER_ID)
+            .await
+            .unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.clone().listen(listener));
+
+    let mut handshake = Handshake::new(torrent.info_hash().unwrap(), CLIENT_ID);
+    let stream = handshake.send(addr).await.unwrap();
+    let mut frame = Framed::new(stream, MessageFrame);
+    let bitfield = frame.next().await.unwrap().unwrap();
+    assert_eq!(bitfield.id, MessageType::Bitfield);
+    assert_eq!(bitfield.payload, [0b1000_0000]);
+
+    // The piece is written and verified by the download, which hands it to the seeder.
+    std::fs::write(file.path(), &data).unwrap();
+    seeder.add_piece(1);
+
+    let have = tokio::time::timeout(Duration::from_secs(5), frame.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(have.id, MessageType::Have);
+    assert_eq!(Have::load_from_payload(&have.payload).unwrap().index, 1);
+}
@@ -0,0 +1,47 @@
+use bittorrent_starter_rust::torrent::{info_hash_of_bytes, read_torrent_file};
+
+#[test]
+fn info_hash_of_sample_torrent() {
+    let raw = std::fs::read("sample.torrent").unwrap();
+    let info_hash = info_hash_of_bytes(&raw).unwrap();
+
+    assert_eq!(
+        hex::encode(info_hash),
+        "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+    );
+    assert_eq!(
+        info_hash,
+        read_torrent_file("sample.torrent")
+            .unwrap()
+            .info_hash()
+            .unwrap()
+    );
+}
+
+#[test]
+fn info_hash_of_bytes_without_info() {
+    assert!(info_hash_of_bytes(b"d8:announce3:urle").is_err());
+    assert!(info_hash_of_bytes(b"d4:infoi1ee").is_err());
+    assert!(info_hash_of_bytes(b"not bencode").is_err());
+}
+
+#[test]
+fn piece_hashes_past_the_last_piece() {
+    let torrent = read_torrent_file("sample.torrent").unwrap();
+    let hashes = &torrent.info.pieces;
+    let num_pieces = hashes.num_pieces();
+
+    assert_eq!(hashes.get(0), Some(&hashes[0]));
+    assert_eq!(hashes.get(num_pieces - 1), Some(&hashes[num_pieces - 1]));
+    assert_eq!(hashes.get(num_pieces), None);
+    assert_eq!(hashes.iter().count(), num_pieces);
+}
+
+#[test]
+#[should_panic]
+fn indexing_piece_hashes_past_the_last_piece_panics() {
+    let torrent = read_torrent_file("sample.torrent").unwrap();
+    let num_pieces = torrent.info.pieces.num_pieces();
+
+    _ = torrent.info.pieces[num_pieces];
+}
@@ -0,0 +1,66 @@
+use bittorrent_starter_rust::tracker::TrackerRequest;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// A gzip member holding the data in a single stored (uncompressed) DEFLATE block.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    gz.push(1);
+    gz.extend((data.len() as u16).to_le_bytes());
+    gz.extend((!(data.len() as u16)).to_le_bytes());
+    gz.extend(data);
+    gz.extend(crc32(data).to_le_bytes());
+    gz.extend((data.len() as u32).to_le_bytes());
+    gz
+}
+
+// Answer a single request with the body, gzipped.
+async fn serve_gzipped(listener: TcpListener, body: Vec<u8>) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        request.push(stream.read_u8().await.unwrap());
+    }
+
+    let body = gzip(&body);
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&body).await.unwrap();
+}
+
+#[tokio::test]
+async fn announce_to_tracker_answering_gzip() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let server = tokio::spawn(serve_gzipped(
+        listener,
+        b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e".to_vec(),
+    ));
+
+    let response = TrackerRequest::new([1; 20], 6881, 100)
+        .send(&url, [2; 20])
+        .await
+        .unwrap();
+    server.await.unwrap();
+
+    assert_eq!(response.interval, Some(900));
+    assert_eq!(response.peers.0, vec!["127.0.0.1:6881".parse().unwrap()]);
+}